@@ -0,0 +1,288 @@
+//! Just enough gzip ([RFC 1952] framing around [RFC 1951] deflate) to compress text responses without any dependencies.
+//!
+//! Only the fixed Huffman codes are used, fed by a simple hash chain LZ77 matcher - nowhere near `zlib`'s ratios,
+//! but HTML / CSS / JS still typically shrink by half or more.
+//!
+//! [RFC 1951]: https://datatracker.ietf.org/doc/html/rfc1951
+//! [RFC 1952]: https://datatracker.ietf.org/doc/html/rfc1952
+
+const WINDOW    : usize = 32 * 1024;
+const MIN_MATCH : usize = 3;
+const MAX_MATCH : usize = 258;
+const MAX_CHAIN : usize = 64; // candidates considered per position - trades ratio for speed
+const HASH_BITS : u32 = 15;
+const NONE      : usize = usize::MAX;
+
+const LENGTH_BASE   : [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA  : [u8;  29] = [0, 0, 0, 0, 0, 0, 0,  0,  1,  1,  1,  1,  2,  2,  2,  2,  3,  3,  3,  3,  4,  4,  4,   4,   5,   5,   5,   5,   0];
+const DIST_BASE     : [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA    : [u8;  30] = [0, 0, 0, 0, 1, 1, 2,  2,  3,  3,  4,  4,  5,  5,   6,   6,   7,   7,   8,   8,    9,    9,   10,   10,   11,   11,   12,    12,    13,    13];
+
+/// Compress `data` into a complete gzip member.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // magic, CM = deflate, no FLG, no MTIME, no XFL, OS = unknown
+    let header = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    let mut bits = BitWriter { out: header, acc: 0, len: 0 };
+    bits.put(1, 1);     // BFINAL - everything goes in one block
+    bits.put(0b01, 2);  // BTYPE = fixed Huffman codes
+
+    let mut head = vec![NONE; 1 << HASH_BITS]; // most recent position of each hash
+    let mut prev = vec![NONE; WINDOW];         // previous position with the same hash, indexed by position % WINDOW
+    let mut i = 0;
+    while i < data.len() {
+        let (len, dist) = longest_match(data, i, &head, &prev);
+        if len >= MIN_MATCH {
+            bits.length(len);
+            bits.distance(dist);
+        } else {
+            bits.symbol(data[i].into());
+        }
+        for pos in i .. i + len.max(1) { insert(data, pos, &mut head, &mut prev) }
+        i += len.max(1);
+    }
+    bits.symbol(256); // end of block
+
+    let mut out = bits.finish();
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes()); // ISIZE is modulo 2^32
+    out
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    let v = u32::from_le_bytes([data[pos], data[pos+1], data[pos+2], 0]);
+    (v.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+fn insert(data: &[u8], pos: usize, head: &mut [usize], prev: &mut [usize]) {
+    if pos + MIN_MATCH > data.len() { return }
+    let h = hash(data, pos);
+    prev[pos % WINDOW] = head[h];
+    head[h] = pos;
+}
+
+/// The longest earlier match for `data[pos..]` as `(length, distance)`, or a length of 0 if there isn't one.
+fn longest_match(data: &[u8], pos: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if pos + MIN_MATCH > data.len() { return (0, 0) }
+    let max_len = MAX_MATCH.min(data.len() - pos);
+    let mut best = (0, 0);
+    let mut candidate = head[hash(data, pos)];
+    for _ in 0 .. MAX_CHAIN {
+        // Anything further back than the window has had its `prev` slot reused, so the chain ends there.  A distance of exactly
+        // `WINDOW` is still fine: `pos`, which will reuse that slot, hasn't been inserted yet.
+        if candidate == NONE || pos - candidate > WINDOW { break }
+        let len = data[candidate..].iter().zip(&data[pos .. pos + max_len]).take_while(|(a, b)| a == b).count();
+        if len > best.0 {
+            best = (len, pos - candidate);
+            if len == max_len { break }
+        }
+        let next = prev[candidate % WINDOW];
+        if next == NONE || next >= candidate { break }
+        candidate = next;
+    }
+    best
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    len: u32, // bits buffered in `acc`
+}
+
+impl BitWriter {
+    /// Append the low `bits` of `value`, least significant bit first.
+    fn put(&mut self, value: u32, bits: u32) {
+        self.acc |= u64::from(value) << self.len;
+        self.len += bits;
+        while self.len >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Append a Huffman code, which deflate packs most significant bit first.
+    fn code(&mut self, code: u16, bits: u32) {
+        self.put(u32::from(code.reverse_bits() >> (16 - bits)), bits);
+    }
+
+    /// Append a literal/length alphabet symbol (0 ..= 285) using the fixed codes of RFC 1951 § 3.2.6.
+    fn symbol(&mut self, symbol: u16) {
+        match symbol {
+            0   ..= 143 => self.code(0x30  + symbol,       8),
+            144 ..= 255 => self.code(0x190 + symbol - 144, 9),
+            256 ..= 279 => self.code(        symbol - 256, 7),
+            _           => self.code(0xc0  + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, len: usize) {
+        let index = LENGTH_BASE.iter().rposition(|&base| usize::from(base) <= len).unwrap();
+        self.symbol(257 + index as u16);
+        self.put((len - usize::from(LENGTH_BASE[index])) as u32, LENGTH_EXTRA[index].into());
+    }
+
+    fn distance(&mut self, dist: usize) {
+        let index = DIST_BASE.iter().rposition(|&base| usize::from(base) <= dist).unwrap();
+        self.code(index as u16, 5);
+        self.put((dist - usize::from(DIST_BASE[index])) as u32, DIST_EXTRA[index].into());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 { self.out.push(self.acc as u8) }
+        self.out
+    }
+}
+
+const CRC_TABLE : [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| CRC_TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The reading half of [`BitWriter`].
+    struct BitReader<'d> {
+        data:   &'d [u8],
+        pos:    usize, // in bits
+    }
+
+    impl BitReader<'_> {
+        /// The next `bits`, least significant bit first.
+        fn get(&mut self, bits: u32) -> u32 {
+            (0 .. bits).fold(0, |value, bit| {
+                let b = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+                self.pos += 1;
+                value | u32::from(b) << bit
+            })
+        }
+
+        /// The next `bits` of a Huffman code, most significant bit first.
+        fn code(&mut self, bits: u32) -> u32 {
+            (0 .. bits).fold(0, |code, _| code << 1 | self.get(1))
+        }
+
+        /// The next literal/length alphabet symbol, in the fixed codes of RFC 1951 § 3.2.6.
+        fn symbol(&mut self) -> u16 {
+            let code = self.code(7);
+            if code <= 0x17 { return 256 + code as u16 }
+            let code = code << 1 | self.get(1);
+            match code {
+                0x30 ..= 0xBF   => (code - 0x30) as u16,
+                0xC0 ..= 0xC7   => (code - 0xC0 + 280) as u16,
+                _               => ((code << 1 | self.get(1)) - 0x190 + 144) as u16,
+            }
+        }
+
+        fn length(&mut self, symbol: u16) -> usize {
+            let index = usize::from(symbol - 257);
+            usize::from(LENGTH_BASE[index]) + self.get(LENGTH_EXTRA[index].into()) as usize
+        }
+
+        fn distance(&mut self) -> usize {
+            let index = self.code(5) as usize;
+            usize::from(DIST_BASE[index]) + self.get(DIST_EXTRA[index].into()) as usize
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Token { Literal(u8), Match(usize, usize) } // (length, distance)
+
+    /// Inflate a member written by [`compress`], checking its header and trailer - returning the data, and the tokens it was coded as.
+    fn decompress(gz: &[u8]) -> (Vec<u8>, Vec<Token>) {
+        assert_eq!(gz[..10], [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]);
+        let mut bits = BitReader { data: &gz[10..], pos: 0 };
+        assert_eq!((bits.get(1), bits.get(2)), (1, 0b01), "expected a single, final, fixed Huffman block");
+        let (mut data, mut tokens) = (Vec::new(), Vec::new());
+        loop {
+            match bits.symbol() {
+                256             => break,
+                literal @ 0 ..= 255 => {
+                    data.push(literal as u8);
+                    tokens.push(Token::Literal(literal as u8));
+                },
+                symbol          => {
+                    let (len, dist) = (bits.length(symbol), bits.distance());
+                    for _ in 0 .. len { data.push(data[data.len() - dist]) }
+                    tokens.push(Token::Match(len, dist));
+                },
+            }
+        }
+        let trailer = &gz[10 + bits.pos.div_ceil(8) ..];
+        assert_eq!(trailer, [crc32(&data).to_le_bytes(), (data.len() as u32).to_le_bytes()].concat());
+        (data, tokens)
+    }
+
+    /// Bytes without much to match - an xorshift generator's.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_F491_u32;
+        (0 .. len).map(|_| { state ^= state << 13; state ^= state >> 17; state ^= state << 5; state as u8 }).collect()
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn short_literals_get_the_fixed_codes() {
+        // As `zlib` codes them, with `Z_FIXED`.
+        let header = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+        assert_eq!(compress(b"a"), [&header[..], &[0x4b, 0x04, 0x00], &0xe8b7_be43_u32.to_le_bytes(), &[1, 0, 0, 0]].concat());
+        assert_eq!(compress(b"Hi!\xff"), [&header[..], &[0xf3, 0xc8, 0x54, 0xfc, 0x0f, 0x00], &0x997f_a50f_u32.to_le_bytes(), &[4, 0, 0, 0]].concat());
+    }
+
+    #[test]
+    fn compressed_data_inflates_back() {
+        let all_bytes = (0 ..= 255).collect::<Vec<u8>>();
+        let repetitive = b"<li>chat</li>\n".repeat(500);
+        for data in [&b""[..], b"a", b"aaa", &all_bytes, &repetitive, include_bytes!("index.html"), &noise(100_000)] {
+            assert_eq!(decompress(&compress(data)).0, data);
+        }
+        assert!(compress(&repetitive).len() < repetitive.len() / 10);
+    }
+
+    #[test]
+    fn matches_reach_the_longest_length_and_furthest_distance() {
+        let (data, tokens) = decompress(&compress(&[b'a'; 1 + 258 * 2]));
+        assert_eq!(data, [b'a'; 1 + 258 * 2]);
+        assert_eq!(tokens, [Token::Literal(b'a'), Token::Match(258, 1), Token::Match(258, 1)]);
+
+        let mut far = noise(WINDOW);
+        far.extend_from_within(.. MAX_MATCH);
+        let (data, tokens) = decompress(&compress(&far));
+        assert_eq!(data, far);
+        assert_eq!(tokens.last(), Some(&Token::Match(258, 32768)));
+    }
+
+    #[test]
+    fn every_length_and_distance_codes_its_extra_bits() {
+        let distances = DIST_BASE.iter().zip(DIST_EXTRA).flat_map(|(&base, extra)| [usize::from(base), usize::from(base) + (1 << extra) - 1]);
+        for (len, dist) in (MIN_MATCH ..= MAX_MATCH).zip(distances.cycle()) { // every length, with every edge of every distance code
+            let mut bits = BitWriter { out: Vec::new(), acc: 0, len: 0 };
+            bits.length(len);
+            bits.distance(dist);
+            let coded = bits.finish();
+            let mut bits = BitReader { data: &coded, pos: 0 };
+            let symbol = bits.symbol();
+            assert_eq!((bits.length(symbol), bits.distance()), (len, dist));
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Just enough [RFC 8259] JSON to read small request bodies and quote strings in responses, without `serde`.
+//!
+//! [RFC 8259]: https://datatracker.ietf.org/doc/html/rfc8259
+
+#[allow(dead_code)] // every kind of value is parsed, even if only some are used so far
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// The value of `key`, if this is an object containing it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        let Json::Object(members) = self else { return None };
+        members.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        let Json::String(s) = self else { return None };
+        Some(s)
+    }
+}
+
+/// Parse a complete JSON document, or [`None`] if it's malformed (or nested unreasonably deep).
+pub fn parse(text: &str) -> Option<Json> {
+    let mut parser = Parser { text: text.as_bytes(), pos: 0, depth: 0 };
+    let value = parser.value()?;
+    parser.whitespace();
+    (parser.pos == parser.text.len()).then_some(value)
+}
+
+/// `s` as a quoted, escaped JSON string - safe to embed in a single line, since control characters are always escaped.
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"'                 => quoted.push_str("\\\""),
+            '\\'                => quoted.push_str("\\\\"),
+            '\n'                => quoted.push_str("\\n"),
+            '\r'                => quoted.push_str("\\r"),
+            '\t'                => quoted.push_str("\\t"),
+            c if c < ' '        => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            '\u{2028}'          => quoted.push_str("\\u2028"), // JS line terminators, for older `eval`-era consumers
+            '\u{2029}'          => quoted.push_str("\\u2029"),
+            c                   => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+const MAX_DEPTH : usize = 32;
+
+struct Parser<'t> {
+    text:   &'t [u8],
+    pos:    usize,
+    depth:  usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> { self.text.get(self.pos).copied() }
+
+    fn whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) { self.pos += 1 }
+    }
+
+    fn literal(&mut self, literal: &str, value: Json) -> Option<Json> {
+        let rest = self.text[self.pos..].strip_prefix(literal.as_bytes())?;
+        self.pos = self.text.len() - rest.len();
+        Some(value)
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.whitespace();
+        match self.peek()? {
+            b'n'                    => self.literal("null",  Json::Null),
+            b't'                    => self.literal("true",  Json::Bool(true)),
+            b'f'                    => self.literal("false", Json::Bool(false)),
+            b'"'                    => self.string().map(Json::String),
+            b'-' | b'0' ..= b'9'    => self.number(),
+            b'[' | b'{'             => self.container(),
+            _                       => None,
+        }
+    }
+
+    fn container(&mut self) -> Option<Json> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH { return None }
+        let object = self.peek()? == b'{';
+        let close = if object { b'}' } else { b']' };
+        self.pos += 1;
+
+        let mut elements = Vec::new();
+        let mut members = Vec::new();
+        self.whitespace();
+        if self.peek()? == close {
+            self.pos += 1;
+        } else {
+            loop {
+                if object {
+                    self.whitespace();
+                    if self.peek()? != b'"' { return None }
+                    let key = self.string()?;
+                    self.whitespace();
+                    if self.peek()? != b':' { return None }
+                    self.pos += 1;
+                    members.push((key, self.value()?));
+                } else {
+                    elements.push(self.value()?);
+                }
+                self.whitespace();
+                match self.peek()? {
+                    b','            => self.pos += 1,
+                    b if b == close => { self.pos += 1; break },
+                    _               => return None,
+                }
+            }
+        }
+        self.depth -= 1;
+        Some(if object { Json::Object(members) } else { Json::Array(elements) })
+    }
+
+    fn number(&mut self) -> Option<Json> {
+        let start = self.pos;
+        if self.peek() == Some(b'-') { self.pos += 1 }
+        let digits = |p: &mut Self| { let start = p.pos; while p.peek().is_some_and(|b| b.is_ascii_digit()) { p.pos += 1 } p.pos > start };
+        match self.peek()? {
+            b'0'                => self.pos += 1, // no leading zeros
+            b'1' ..= b'9'       => { digits(self); },
+            _                   => return None,
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if !digits(self) { return None }
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) { self.pos += 1 }
+            if !digits(self) { return None }
+        }
+        std::str::from_utf8(&self.text[start..self.pos]).ok()?.parse().ok().map(Json::Number)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.pos += 1; // opening quote
+        let mut s = Vec::new();
+        loop {
+            let b = self.peek()?;
+            self.pos += 1;
+            match b {
+                b'"'        => return String::from_utf8(s).ok(),
+                b'\\'       => {
+                    let escaped = self.peek()?;
+                    self.pos += 1;
+                    let ch = match escaped {
+                        b'"'    => '"',
+                        b'\\'   => '\\',
+                        b'/'    => '/',
+                        b'b'    => '\u{8}',
+                        b'f'    => '\u{C}',
+                        b'n'    => '\n',
+                        b'r'    => '\r',
+                        b't'    => '\t',
+                        b'u'    => {
+                            let unit = self.hex4()?;
+                            if (0xD800 .. 0xDC00).contains(&unit) {
+                                // A high surrogate, which must be followed by an escaped low surrogate.
+                                if !self.text[self.pos..].starts_with(b"\\u") { return None }
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                if !(0xDC00 .. 0xE000).contains(&low) { return None }
+                                char::from_u32(0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00))?
+                            } else {
+                                char::from_u32(unit)? // lone low surrogates are rejected here
+                            }
+                        },
+                        _       => return None,
+                    };
+                    s.extend(ch.encode_utf8(&mut [0; 4]).bytes());
+                },
+                0x00 ..= 0x1F => return None, // control characters must be escaped
+                b           => s.push(b),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let hex = self.text.get(self.pos .. self.pos + 4)?;
+        self.pos += 4;
+        u32::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok().filter(|_| hex.iter().all(u8::is_ascii_hexdigit))
+    }
+}
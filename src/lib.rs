@@ -0,0 +1,3139 @@
+//! A minimal HTTP chat server - Server-Sent Events and WebSockets out, POSTs and WebSocket messages in - using nothing but `std`.
+//! See `main.rs` for the command line front end.
+
+use std::fmt::Display;
+use std::hash::BuildHasher;
+use std::io::{self, Read, Write, BufWriter};
+use std::path::{Path, PathBuf};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream, TcpListener};
+use std::borrow::Cow;
+use std::cell::Cell;
+use json::Json;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::sync::{Condvar, Mutex, Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, sync_channel};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod gzip;
+mod json;
+mod websocket;
+
+/// Minimal leveled logging to stderr - see `--log-level`.
+#[macro_use] pub mod log {
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicU8, Ordering::SeqCst};
+
+    #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Level { Error, Warn, Info, Debug }
+
+    static LEVEL : AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+    pub fn set_level(level: Level) { LEVEL.store(level as u8, SeqCst) }
+    pub fn enabled(level: Level) -> bool { level as u8 <= LEVEL.load(SeqCst) }
+
+    impl FromStr for Level {
+        type Err = &'static str;
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                _ if s.eq_ignore_ascii_case("error")    => Ok(Level::Error),
+                _ if s.eq_ignore_ascii_case("warn")     => Ok(Level::Warn),
+                _ if s.eq_ignore_ascii_case("info")     => Ok(Level::Info),
+                _ if s.eq_ignore_ascii_case("debug")    => Ok(Level::Debug),
+                _                                       => Err("expected error, warn, info, or debug"),
+            }
+        }
+    }
+
+    macro_rules! log { ($level:expr, $($arg:tt)*) => { if $crate::log::enabled($level) { eprintln!($($arg)*) } } }
+    macro_rules! error { ($($arg:tt)*) => { log!($crate::log::Level::Error, $($arg)*) } }
+    macro_rules! warn  { ($($arg:tt)*) => { log!($crate::log::Level::Warn,  $($arg)*) } }
+    macro_rules! info  { ($($arg:tt)*) => { log!($crate::log::Level::Info,  $($arg)*) } }
+    macro_rules! debug { ($($arg:tt)*) => { log!($crate::log::Level::Debug, $($arg)*) } }
+}
+
+const MAX_REQUEST   : usize = 64 * 1024; // default - see `Config::max_request`
+const MIN_REQUEST   : usize = 4 * 1024; // initial buffer per connection, grown as needed up to `Config::max_request`
+const READ_TIMEOUT  : Duration = Duration::from_secs(10); // default - see `Config::read_timeout`
+const WRITE_TIMEOUT : Duration = Duration::from_secs(10); // default - see `Config::write_timeout`
+const ACCEPT_POLL   : Duration = Duration::from_millis(100); // how often acceptors check for shutdown
+const WRITE_RETRIES : u32 = 2; // further write timeouts a stalled stream subscriber gets, before it's dropped - see `PatientWriter`
+const DRAIN_TIMEOUT : Duration = Duration::from_secs(5);
+const BACKLOG       : usize = 1024; // default - see `Config::backlog`
+const QUEUE_PER_WORKER : usize = 16; // accepted connections waiting on a worker before acceptors block
+const RATE_LIMIT_PRUNE : Duration = Duration::from_secs(60);
+const MAX_ROOMS     : usize = 256;
+const LIVE_BACKLOG  : usize = 64; // presence events retained per room for subscribers that fall behind, before they're skipped - see `Broadcast::send_live`
+const MAX_ROOM_NAME : usize = 64;
+const RESERVED_ROOM_NAMES : &[&str] = &["messages"]; // routes of their own under `/chat/`, which `/chat/{room}` would shadow (or be shadowed by)
+const MAX_USER_NAME : usize = 32; // chars
+const MAX_CONNECTIONS : usize = 1024; // default - see `Server::max_connections`
+const KNOWN_METHODS : &[&str] = &["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"]; // RFC 7231 § 4, RFC 5789
+
+/// Settings that affect how requests are handled, as opposed to how connections are accepted.
+pub struct Config {
+    pub sse_retry_ms:   u64,
+    pub ping_interval:  Duration, // SSE keepalive cadence, independent of `read_timeout` - streams don't time out reads
+    pub read_timeout:   Option<Duration>, // per read of a request, or [`None`] to wait indefinitely
+    pub write_timeout:  Option<Duration>, // per write, including to streams - so a subscriber that stops reading is dropped by the next ping
+    pub history:        usize,    // recent messages replayed to new SSE subscribers
+    pub backlog:        usize,    // messages retained per room for subscribers that fall behind, before they're skipped - see `Broadcast`
+    pub max_message:    usize,    // bytes of (lossily decoded) UTF-8 per posted message
+    pub max_request:    usize,    // bytes of request line, headers, and any body, buffered per connection
+    pub max_headers:    usize,    // header fields per request
+    pub rate_limit:     f64,      // messages per second each IP may post, or 0 for unlimited
+    pub rate_burst:     f64,      // messages each IP may post back-to-back
+    pub dedup_window:   Duration, // how soon an IP repeating its last message to a room is ignored, or zero to allow repeats
+    pub banned_words:   Vec<String>, // lowercase, matched case-insensitively against whole words of posted messages
+    pub filter_mode:    FilterMode, // what happens to messages with `banned_words`
+    pub static_dir:     Option<PathBuf>,
+    pub index_file:     Option<PathBuf>, // reread for every `/`, instead of the embedded page - for working on it without rebuilding
+    pub vhosts:         Vec<(String, PathBuf)>, // lowercase host names, and the page (or directory with an `index.html`) to serve them for `/`
+    pub cors_origin:    Option<String>, // `Access-Control-Allow-Origin` for chat routes
+    pub auth:           Option<String>, // "user:pass" required via HTTP Basic authentication
+    pub admin_token:    Option<String>, // required via `Authorization: Bearer` for `/admin/...` - which 404 without one
+    pub history_file:   Option<PathBuf>,
+    pub robots_txt:     Option<String>, // served as `/robots.txt` instead of the built-in one, which disallows everything
+    pub motd:           Option<String>, // sent to each new subscriber after the scrollback, as a `system` event
+    pub allow_raw:      bool,     // whether connections may greet with `CHAT` for a plain text session, instead of using HTTP - not with `auth`
+    pub server_name:    Option<String>, // `Server` header value for every response, or [`None`] to leave it out
+    pub allow_ips:      Vec<Cidr>, // if any, the only peers that may connect - see `Config::admits`
+    pub deny_ips:       Vec<Cidr>, // peers that may not connect, even if allowed
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            sse_retry_ms:   1000,
+            ping_interval:  Duration::from_secs(10),
+            read_timeout:   Some(READ_TIMEOUT),
+            write_timeout:  Some(WRITE_TIMEOUT),
+            history:        100,
+            backlog:        BACKLOG,
+            max_message:    4096,
+            max_request:    MAX_REQUEST,
+            max_headers:    100,
+            rate_limit:     5.0,
+            rate_burst:     10.0,
+            dedup_window:   Duration::from_secs(2),
+            banned_words:   Vec::new(),
+            filter_mode:    FilterMode::Reject,
+            static_dir:     None,
+            index_file:     None,
+            vhosts:         Vec::new(),
+            cors_origin:    None,
+            auth:           None,
+            admin_token:    None,
+            history_file:   None,
+            robots_txt:     None,
+            motd:           None,
+            allow_raw:      false,
+            server_name:    Some(String::from(env!("CARGO_PKG_NAME"))),
+            allow_ips:      Vec::new(),
+            deny_ips:       Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Whether a connection from `ip` is permitted by `deny_ips` and `allow_ips` - denials win, and no allowances permit anyone.
+    fn admits(&self, ip: IpAddr) -> bool {
+        !self.deny_ips.iter().any(|net| net.contains(ip)) && (self.allow_ips.is_empty() || self.allow_ips.iter().any(|net| net.contains(ip)))
+    }
+}
+
+/// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `fe80::/10` - or a single address, without the prefix length.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    addr:   IpAddr,
+    prefix: u32, // leading bits of `addr` that must match
+}
+
+impl Cidr {
+    /// Whether `ip` is in this range - IPv4-mapped IPv6 addresses count as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net) ^ u32::from(ip)).checked_shr(32 - self.prefix).unwrap_or(0) == 0,
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net) ^ u128::from(ip)).checked_shr(128 - self.prefix).unwrap_or(0) == 0,
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(addr, prefix)| (addr, Some(prefix)));
+        let addr = addr.parse::<IpAddr>().map_err(|_| "expected an IP address, optionally followed by /prefix-length")?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None            => bits,
+            Some(prefix)    => prefix.parse().ok().filter(|p| *p <= bits && !prefix.starts_with('+')).ok_or("prefix length out of range")?,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// What happens to posted messages containing any of `Config::banned_words`.
+#[derive(Clone, Copy)]
+pub enum FilterMode {
+    /// Refuse them - `422 Unprocessable Entity`, for POSTs.
+    Reject,
+    /// Post them with each banned word replaced by asterisks.
+    Mask,
+}
+
+impl std::str::FromStr for FilterMode {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            _ if s.eq_ignore_ascii_case("reject")   => Ok(FilterMode::Reject),
+            _ if s.eq_ignore_ascii_case("mask")     => Ok(FilterMode::Mask),
+            _                                       => Err("expected reject or mask"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Common {
+    config:         Config,
+    rooms:          Mutex<HashMap<String, Arc<Broadcast>>>,
+    rate_limiter:   Mutex<RateLimiter>,
+    last_messages:  Mutex<LastMessages>,
+    shutdown:       AtomicBool,
+    stop_requested: Arc<AtomicBool>, // by `/admin/shutdown` or a `ShutdownHandle`, as alternatives to `ctrl_c`
+    streams:        Mutex<(u64, HashMap<u64, Arc<StreamStats>>)>, // the next id, and SSE / NDJSON streams by id - see `StreamSummary`
+    connections:    AtomicUsize,
+    subscribers:    AtomicUsize,
+    metrics:        Metrics,
+    history_file:   Mutex<Option<std::fs::File>>, // opened for appending - see `--history-file`
+    router:         Router,
+    started:        u64, // Unix seconds - also the embedded page's `Last-Modified`
+}
+
+impl Common {
+    /// The chat room named `name`, created on first use - or [`None`] if shutting down or there are too many rooms.
+    fn room(&self, name: &str) -> Option<Arc<Broadcast>> {
+        let mut rooms = self.rooms.lock().unwrap();
+        if self.shutdown.load(SeqCst) { return None }
+        if let Some(room) = rooms.get(name) { return Some(Arc::clone(room)) }
+        // Before counting towards `MAX_ROOMS`, forget rooms with no scrollback that nobody's using (only `rooms` itself still refers to them),
+        // so rooms that were merely visited don't add up.  They'd be recreated just as they were anyways.
+        rooms.retain(|_, room| Arc::strong_count(room) > 1 || !room.is_empty());
+        if rooms.len() >= MAX_ROOMS { return None }
+        let room = Arc::new(Broadcast::new(name, self.config.history.max(self.config.backlog).max(1)));
+        rooms.insert(name.to_string(), Arc::clone(&room));
+        Some(room)
+    }
+
+    /// Broadcast `message` from `user` to `room`'s subscribers as an SSE event, escaped for display as HTML.
+    fn post(&self, room: &Broadcast, user: &str, message: &str) {
+        let time = unix_millis(SystemTime::now());
+        let message = format!("{user}: {message}"); // attributed in a way plain `onmessage` clients still display
+        // Every line becomes its own `data:` field, so nothing posted can start a field of its own - SSE also ends lines at a lone CR, which `lines` doesn't.
+        let message = message.replace("\r\n", "\n").replace('\r', "\n");
+        let message = message.lines().map(|line| format!("data: {}\n", html_escape(line))).collect::<Vec<_>>().join("");
+        // EventSource drops fields it doesn't know, so the time gets the first `data` line - a line of digits, which no attributed message can be.
+        // `message` is the event type EventSource assumes anyways, so `onmessage` still works - naming it tells chat apart from `presence` etc.
+        self.broadcast(room, time, format!("event: message\ndata: {time}\n{message}\n"));
+    }
+
+    /// Forget `room`'s scrollback - including in the history file, when next loaded - and tell subscribers to with a `cleared` SSE event.
+    fn clear(&self, room: &Broadcast) {
+        room.clear();
+        let time = unix_millis(SystemTime::now());
+        self.broadcast(room, time, format!("event: cleared\ndata: {{\"time\":{time}}}\n\n"));
+    }
+
+    /// The chat room named `name`, if it's been used - for looking at a room without creating it.
+    fn existing_room(&self, name: &str) -> Option<Arc<Broadcast>> {
+        self.rooms.lock().unwrap().get(name).cloned()
+    }
+
+    /// Disconnect everyone subscribed to the room named `name`, which starts afresh (without scrollback) when next used.
+    fn kick(&self, name: &str) {
+        let room = self.rooms.lock().unwrap().remove(name);
+        if let Some(room) = room { room.close() }
+    }
+
+    /// `text` as it should be posted, given `Config::banned_words` - or [`None`] if it should be refused.
+    fn filter<'t>(&self, text: &'t str) -> Option<Cow<'t, str>> {
+        let Some(masked) = mask_banned_words(text, &self.config.banned_words) else { return Some(text.into()) };
+        match self.config.filter_mode {
+            FilterMode::Reject  => None,
+            FilterMode::Mask    => Some(masked.into()),
+        }
+    }
+
+    /// Whether `peer` just posted `text` to `room` already - see `Config::dedup_window`.
+    fn is_repeat(&self, peer: Peer, room: &Broadcast, text: &str) -> bool {
+        let Some(peer) = peer.0 else { return false };
+        self.last_messages.lock().unwrap().repeat(peer.ip(), &room.name, text, self.config.dedup_window)
+    }
+
+    /// Broadcast a structured `chat` SSE event, with JSON data left for the client to display safely.
+    fn post_chat(&self, room: &Broadcast, user: &str, text: &str) {
+        let time = unix_millis(SystemTime::now());
+        self.broadcast(room, time, format!("event: chat\ndata: {{\"user\":{},\"text\":{},\"time\":{time}}}\n\n", json::quote(user), json::quote(text)));
+    }
+
+    /// Send a chat message's SSE `event`, posted at `time`, to `room` - and record it in the history file (if any).
+    ///
+    /// `event` should carry `time` itself, so replaying it (e.g. from the history file) keeps the original timestamp.
+    fn broadcast(&self, room: &Broadcast, time: u64, event: String) {
+        if let Some(file) = &mut *self.history_file.lock().unwrap() {
+            // One JSON object per line, written with a single call so a crash can at worst truncate the last line.
+            let line = format!("{{\"time\":{time},\"room\":{},\"event\":{}}}\n", json::quote(&room.name), json::quote(&event));
+            if let Err(e) = file.write_all(line.as_bytes()).and_then(|()| file.flush()) { warn!("unable to append to history file: {e}") }
+        }
+        room.send(Arc::new(event));
+        self.metrics.messages.fetch_add(1, SeqCst);
+    }
+
+    /// Replay a history file written by [`Self::broadcast`] into the rooms it names - only the most recent messages of each are retained.
+    fn load_history(&self, path: &Path) -> io::Result<()> {
+        let file = match std::fs::File::open(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()), // nothing to load yet
+            other => other?,
+        };
+        for line in io::BufRead::lines(io::BufReader::new(file)) {
+            let line = line?;
+            let Some(record) = json::parse(&line) else { continue }; // e.g. torn by a crash mid-write
+            let (Some(room), Some(event)) = (record.get("room").and_then(Json::as_str), record.get("event").and_then(Json::as_str)) else { continue };
+            if !is_valid_room_name(room) { continue }
+            let Some(room) = self.room(room) else { continue };
+            if event.starts_with("event: cleared\n") { room.clear() } // see `Self::clear`
+            room.send(Arc::new(event.to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn is_valid_room_name(name: &str) -> bool {
+    (1 ..= MAX_ROOM_NAME).contains(&name.len()) && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') && !RESERVED_ROOM_NAMES.contains(&name)
+}
+
+/// `name` without control characters or surrounding whitespace, and truncated to [`MAX_USER_NAME`] - or [`None`] if nothing's left.
+fn sanitize_user_name(name: &str) -> Option<String> {
+    let name = name.chars().filter(|c| !c.is_control()).collect::<String>();
+    let name = name.trim().chars().take(MAX_USER_NAME).collect::<String>();
+    (!name.is_empty()).then_some(name)
+}
+
+/// `text` with each whole word in `banned` (lowercase) replaced by as many asterisks - or [`None`] if it has none of them.
+fn mask_banned_words(text: &str, banned: &[String]) -> Option<String> {
+    if banned.is_empty() { return None }
+    let mut masked = String::with_capacity(text.len());
+    let mut found = false;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let word_len = rest.find(|c: char| !c.is_alphanumeric()).unwrap_or(rest.len());
+        let (word, after) = rest.split_at(word_len);
+        if !word.is_empty() && banned.contains(&word.to_lowercase()) {
+            masked.extend(word.chars().map(|_| '*'));
+            found = true;
+        } else {
+            masked.push_str(word);
+        }
+        let gap_len = after.find(char::is_alphanumeric).unwrap_or(after.len());
+        masked.push_str(&after[..gap_len]);
+        rest = &after[gap_len..];
+    }
+    found.then_some(masked)
+}
+
+/// Counters for `/metrics` - bumped while serving, only formatted when scraped.
+#[derive(Default)]
+struct Metrics {
+    requests:   AtomicU64,
+    responses:  [AtomicU64; 5], // by status class: 1xx ..= 5xx
+    messages:   AtomicU64,      // broadcast to a room
+    read_bytes: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a response by the class of its `status` line (e.g. `"404 Not Found"` counts towards 4xx).
+    fn responded(&self, status: &str) {
+        let class = usize::from(status.as_bytes()[0].wrapping_sub(b'1'));
+        if let Some(responses) = self.responses.get(class) { responses.fetch_add(1, SeqCst); }
+    }
+
+    /// Prometheus text exposition format, version 0.0.4.
+    fn prometheus_text(&self, common: &Common) -> String {
+        use std::fmt::Write;
+        let mut text = String::new();
+        let _ = writeln!(text, "# HELP chat_requests_total HTTP requests received.");
+        let _ = writeln!(text, "# TYPE chat_requests_total counter");
+        let _ = writeln!(text, "chat_requests_total {}", self.requests.load(SeqCst));
+        let _ = writeln!(text, "# HELP chat_responses_total HTTP responses sent, by status class.");
+        let _ = writeln!(text, "# TYPE chat_responses_total counter");
+        for (class, responses) in (1..).zip(&self.responses) {
+            let _ = writeln!(text, "chat_responses_total{{class=\"{class}xx\"}} {}", responses.load(SeqCst));
+        }
+        let _ = writeln!(text, "# HELP chat_subscribers Currently connected SSE and WebSocket subscribers.");
+        let _ = writeln!(text, "# TYPE chat_subscribers gauge");
+        let _ = writeln!(text, "chat_subscribers {}", common.subscribers.load(SeqCst));
+        let _ = writeln!(text, "# HELP chat_messages_total Chat messages broadcast.");
+        let _ = writeln!(text, "# TYPE chat_messages_total counter");
+        let _ = writeln!(text, "chat_messages_total {}", self.messages.load(SeqCst));
+        let _ = writeln!(text, "# HELP chat_read_bytes_total Bytes read from clients.");
+        let _ = writeln!(text, "# TYPE chat_read_bytes_total counter");
+        let _ = writeln!(text, "chat_read_bytes_total {}", self.read_bytes.load(SeqCst));
+        text
+    }
+}
+
+/// A bounded ring buffer of recent messages, read independently by any number of subscriber cursors.
+///
+/// Posting appends once and never waits on subscribers.  A subscriber that falls more than `capacity`
+/// messages behind is told how many it missed via [`Recv::Lagged`] and skips ahead to the oldest retained message.
+///
+/// Alongside the ring, [live](Self::send_live) messages (e.g. presence) go only to current subscribers: without ids, they never make scrollback.
+struct Broadcast {
+    name:           String, // of the room
+    state:          Mutex<BroadcastState>,
+    posted:         Condvar,
+    subscribers:    AtomicUsize, // see `PresenceGuard`
+}
+
+struct BroadcastState {
+    messages:   VecDeque<Arc<String>>,
+    first_id:   u64, // id of `messages[0]`
+    capacity:   usize,
+    live:       VecDeque<(u64, Arc<String>)>, // with the `end_id` when each was sent, to order them among `messages`
+    live_first: u64, // sequence number of `live[0]`
+    closed:     bool,
+}
+
+impl BroadcastState {
+    fn end_id(&self) -> u64 { self.first_id + self.messages.len() as u64 }
+    fn live_end(&self) -> u64 { self.live_first + self.live.len() as u64 }
+}
+
+/// A subscriber's position in a [`Broadcast`]: the next message id, and the next live message's sequence number.
+#[derive(Clone, Copy)]
+struct Cursor { id: u64, live: u64 }
+
+enum Recv {
+    Messages(Vec<(Option<u64>, Arc<String>)>), // live messages have no id
+    Lagged(u64),
+}
+
+impl Broadcast {
+    fn new(name: &str, capacity: usize) -> Self {
+        Self {
+            name:           name.to_string(),
+            state:          Mutex::new(BroadcastState { messages: VecDeque::with_capacity(capacity), first_id: 1, capacity, live: VecDeque::new(), live_first: 0, closed: false }),
+            posted:         Condvar::new(),
+            subscribers:    AtomicUsize::new(0),
+        }
+    }
+
+    fn send(&self, message: Arc<String>) {
+        let evicted = {
+            let mut state = self.state.lock().unwrap();
+            let evicted = if state.messages.len() == state.capacity {
+                state.first_id += 1;
+                state.messages.pop_front()
+            } else {
+                None
+            };
+            state.messages.push_back(message);
+            evicted
+        };
+        // Wake subscribers and free the evicted message outside the lock, so they don't immediately block on us.
+        self.posted.notify_all();
+        drop(evicted);
+    }
+
+    /// Send `message` to current subscribers only, outside the ring - it gets no id, and isn't replayed to later ones.
+    ///
+    /// Only the last [`LIVE_BACKLOG`] are kept for subscribers that fall behind, and those that fall further just skip the rest silently.
+    fn send_live(&self, message: Arc<String>) {
+        let evicted = {
+            let mut state = self.state.lock().unwrap();
+            let evicted = if state.live.len() == LIVE_BACKLOG {
+                state.live_first += 1;
+                state.live.pop_front()
+            } else {
+                None
+            };
+            let end_id = state.end_id();
+            state.live.push_back((end_id, message));
+            evicted
+        };
+        self.posted.notify_all();
+        drop(evicted);
+    }
+
+    /// Whether no messages are retained.
+    fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().messages.is_empty()
+    }
+
+    /// Forget every retained message.  Ids carry on regardless, so subscribers that hadn't caught up will see [`Recv::Lagged`].
+    fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.first_id = state.end_id();
+        state.messages.clear();
+    }
+
+    /// Wake every subscriber with [`RecvTimeoutError::Disconnected`] (once they've read what's left) and refuse new ones.
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.posted.notify_all();
+    }
+
+    /// Wake every subscriber, so any whose `cancel` flag was set stop waiting and report [`RecvTimeoutError::Timeout`].
+    fn wake(&self) {
+        let _state = self.state.lock().unwrap(); // so a subscriber between checking `cancel` and waiting can't miss this
+        self.posted.notify_all();
+    }
+
+    /// A cursor positioned after message id `after` - or after every message sent so far - or [`None`] if [closed](Self::close).
+    ///
+    /// Message ids start at 1.  If `after` is no longer retained, the first receive will report [`Recv::Lagged`].
+    fn subscribe(&self, after: Option<u64>) -> Option<Cursor> {
+        let state = self.state.lock().unwrap();
+        let end = state.end_id();
+        (!state.closed).then(|| Cursor { id: after.map_or(end, |id| id.saturating_add(1).min(end)), live: state.live_end() })
+    }
+
+    /// A cursor positioned before the last `count` retained messages, or [`None`] if [closed](Self::close).
+    fn subscribe_recent(&self, count: usize) -> Option<Cursor> {
+        let state = self.state.lock().unwrap();
+        (!state.closed).then(|| Cursor { id: state.end_id() - count.min(state.messages.len()) as u64, live: state.live_end() })
+    }
+
+    /// Wait up to `timeout` for messages after `cursor` - or less, if `cancel` is set and the subscriber [woken](Self::wake).
+    fn recv_timeout(&self, cursor: &mut Cursor, timeout: Duration, cancel: &AtomicBool) -> Result<Recv, RecvTimeoutError> {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self.posted.wait_timeout_while(state, timeout, |state| {
+            !state.closed && cursor.id >= state.end_id() && cursor.live >= state.live_end() && !cancel.load(SeqCst)
+        }).unwrap();
+        if cursor.id < state.first_id {
+            let missed = state.first_id - cursor.id;
+            cursor.id = state.first_id;
+            Ok(Recv::Lagged(missed))
+        } else if cursor.id < state.end_id() || cursor.live < state.live_end() {
+            let mut messages = (cursor.id ..).zip(state.messages.range((cursor.id - state.first_id) as usize ..).cloned()).map(|(id, msg)| (Some(id), msg)).collect::<Vec<_>>();
+            // Each live message goes before the first message sent after it.
+            let live = state.live.range(cursor.live.saturating_sub(state.live_first) as usize ..);
+            for (end_id, msg) in live.rev() {
+                let at = messages.partition_point(|(id, _)| id.is_some_and(|id| id < *end_id));
+                messages.insert(at, (None, Arc::clone(msg)));
+            }
+            *cursor = Cursor { id: state.end_id(), live: state.live_end() };
+            Ok(Recv::Messages(messages))
+        } else if state.closed {
+            Err(RecvTimeoutError::Disconnected)
+        } else {
+            Err(RecvTimeoutError::Timeout)
+        }
+    }
+}
+
+/// Per-IP token buckets limiting how quickly chat messages can be posted.
+struct RateLimiter {
+    buckets:    HashMap<IpAddr, Bucket>,
+    pruned:     Instant,
+}
+
+struct Bucket {
+    tokens:     f64,
+    updated:    Instant,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self { Self { buckets: HashMap::new(), pruned: Instant::now() } }
+}
+
+impl RateLimiter {
+    /// Takes a token from `ip`'s bucket, or returns how long until one will be available.
+    fn take(&mut self, ip: IpAddr, rate: f64, burst: f64) -> Result<(), Duration> {
+        if rate <= 0.0 { return Ok(()) }
+        let now = Instant::now();
+
+        // Full buckets are indistinguishable from missing ones, so forget them now and then.
+        if now.duration_since(self.pruned) >= RATE_LIMIT_PRUNE {
+            self.buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst);
+            self.pruned = now;
+        }
+
+        let bucket = self.buckets.entry(ip).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// The last message each IP posted to each room, to catch accidental double posts.
+struct LastMessages {
+    messages:   HashMap<(IpAddr, String), (String, Instant)>,
+    pruned:     Instant,
+}
+
+impl Default for LastMessages {
+    fn default() -> Self { Self { messages: HashMap::new(), pruned: Instant::now() } }
+}
+
+impl LastMessages {
+    /// Records `text` as `ip`'s last message to `room` - returning whether it repeats the previous one, within `window`.
+    fn repeat(&mut self, ip: IpAddr, room: &str, text: &str, window: Duration) -> bool {
+        if window.is_zero() { return false }
+        let now = Instant::now();
+        if now.duration_since(self.pruned) >= RATE_LIMIT_PRUNE {
+            self.messages.retain(|_, (_, posted)| now.duration_since(*posted) < window);
+            self.pruned = now;
+        }
+
+        match self.messages.insert((ip, room.to_string()), (text.to_string(), now)) {
+            Some((last, posted)) => last == text && now.duration_since(posted) < window,
+            None => false,
+        }
+    }
+}
+
+/// Counts a connection in [`Common::connections`] for as long as it's alive.
+struct ConnectionGuard(Arc<Common>);
+impl ConnectionGuard {
+    fn new(common: &Arc<Common>) -> Self {
+        common.connections.fetch_add(1, SeqCst);
+        Self(Arc::clone(common))
+    }
+}
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) { self.0.connections.fetch_sub(1, SeqCst); }
+}
+
+/// Counts an SSE subscriber in [`Common::subscribers`] for as long as it's streaming.
+struct SubscriberGuard<'c>(&'c Common);
+impl<'c> SubscriberGuard<'c> {
+    fn new(common: &'c Common) -> Self {
+        common.subscribers.fetch_add(1, SeqCst);
+        Self(common)
+    }
+}
+impl Drop for SubscriberGuard<'_> {
+    fn drop(&mut self) { self.0.subscribers.fetch_sub(1, SeqCst); }
+}
+
+/// How a stream is going, for `/admin/subscribers` - and, once it's over, its [`StreamSummary`].
+struct StreamStats {
+    peer:       Peer,
+    room:       String,
+    started:    Instant,
+    messages:   AtomicUsize,
+    bytes:      AtomicU64, // see `CountingWriter`
+}
+
+/// Lists a stream in [`Common::streams`] while it lasts, then logs how it went however it ends - for a sense of how long subscribers stay,
+/// and how much they're sent.
+struct StreamSummary<'c> {
+    common:     &'c Common,
+    id:         u64,
+    stats:      Arc<StreamStats>,
+    reason:     Cell<&'static str>,
+}
+impl<'c> StreamSummary<'c> {
+    fn new(common: &'c Common, peer: Peer, room: &str) -> Self {
+        let stats = Arc::new(StreamStats { peer, room: room.to_string(), started: Instant::now(), messages: AtomicUsize::new(0), bytes: AtomicU64::new(0) });
+        let mut streams = common.streams.lock().unwrap();
+        let id = streams.0;
+        streams.0 += 1;
+        streams.1.insert(id, Arc::clone(&stats));
+        Self { common, id, stats, reason: Cell::new("error") }
+    }
+}
+impl Drop for StreamSummary<'_> {
+    fn drop(&mut self) {
+        self.common.streams.lock().unwrap().1.remove(&self.id);
+        let StreamStats { peer, room, started, messages, bytes } = &*self.stats;
+        let (ms, messages, bytes, reason) = (started.elapsed().as_millis(), messages.load(SeqCst), bytes.load(SeqCst), self.reason.get());
+        info!("stream closed: peer={peer} room={room:?} reason={reason} ms={ms} messages={messages} bytes={bytes}");
+    }
+}
+
+/// Announces a subscriber joining a room, and - however its stream ends - leaving it, as [live](Broadcast::send_live) `presence` events.
+struct PresenceGuard<'r> { room: &'r Broadcast, user: &'r str }
+impl<'r> PresenceGuard<'r> {
+    fn new(room: &'r Broadcast, user: &'r str) -> Self {
+        let count = room.subscribers.fetch_add(1, SeqCst) + 1;
+        room.send_live(presence_event("join", user, count));
+        Self { room, user }
+    }
+}
+impl Drop for PresenceGuard<'_> {
+    fn drop(&mut self) {
+        let count = self.room.subscribers.fetch_sub(1, SeqCst) - 1;
+        self.room.send_live(presence_event("leave", self.user, count));
+    }
+}
+
+fn presence_event(action: &str, user: &str, count: usize) -> Arc<String> {
+    Arc::new(format!("event: presence\ndata: {{\"action\":\"{action}\",\"user\":{},\"count\":{count}}}\n\n", json::quote(user)))
+}
+
+/// A chat server: its listening sockets, plus the settings it will serve them with.
+pub struct Server {
+    listeners:          Vec<TcpListener>,
+    workers:            usize,
+    max_connections:    usize,
+    tcp_keepalive:      Option<Duration>,
+    config:             Config,
+    stop_requested:     Arc<AtomicBool>, // see `ShutdownHandle`
+}
+
+/// Stops a [`Server`] from another thread, as if by Ctrl+C - see [`Server::shutdown_handle`].
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<AtomicBool>);
+
+impl ShutdownHandle {
+    /// Have [`Server::serve`] stop accepting, disconnect subscribers, and return once open connections have drained (or it gives up on them).
+    pub fn shutdown(&self) {
+        self.0.store(true, SeqCst);
+    }
+}
+
+impl Server {
+    /// Listen on every address in `addrs`, with default settings until changed.
+    pub fn bind(addrs: &[SocketAddr]) -> io::Result<Self> {
+        let listeners = addrs.iter().map(|&addr| TcpListener::bind(addr).map_err(|e| io::Error::new(e.kind(), format!("unable to bind {addr}: {e}")))).collect::<io::Result<Vec<_>>>()?;
+        let workers = std::thread::available_parallelism().map_or(4, |n| n.get());
+        Ok(Self { listeners, workers, max_connections: MAX_CONNECTIONS, tcp_keepalive: None, config: Config::default(), stop_requested: Arc::default() })
+    }
+
+    /// The addresses actually bound - useful when binding port 0.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    /// Threads serving requests - which bounds concurrent requests, but not concurrent chat subscribers.  Must be at least 1.
+    pub fn workers(&mut self, workers: usize) -> &mut Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Open connections beyond which new ones get an immediate 503 - each being a thread's attention and a [`Config::max_request`] buffer.
+    /// SSE and WebSocket subscribers keep their connections open, so they count against this too, for as long as they're subscribed.
+    pub fn max_connections(&mut self, max_connections: usize) -> &mut Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Have the OS probe connections idle for `idle`, and reset those whose peers have silently gone (e.g. dropped by a NAT).
+    /// Complements SSE / WebSocket pings, which only notice once a write fails.  Off (the OS default) unless set.
+    pub fn tcp_keepalive(&mut self, idle: Duration) -> &mut Self {
+        self.tcp_keepalive = Some(idle);
+        self
+    }
+
+    pub fn config(&mut self, config: Config) -> &mut Self {
+        self.config = config;
+        self
+    }
+
+    /// A handle to stop [`Self::serve`] with, once it's been moved onto its own thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(Arc::clone(&self.stop_requested))
+    }
+
+    /// Serve until an acceptor fails, [`ctrl_c::requested`], [`ShutdownHandle::shutdown`], or `POST /admin/shutdown`, then give open
+    /// connections up to `DRAIN_TIMEOUT` to finish.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] for [`Config::allow_raw`] with [`Config::auth`]: raw sessions have no way to authenticate.
+    pub fn serve(self) -> io::Result<()> {
+        let Self { listeners, workers, max_connections, tcp_keepalive, config, stop_requested } = self;
+        if config.allow_raw && config.auth.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--allow-raw sessions can't authenticate, so they'd bypass --auth"));
+        }
+        let common = Arc::new(Common { config, stop_requested, started: unix_millis(SystemTime::now()) / 1000, ..Common::default() });
+        if let Some(path) = common.config.history_file.as_deref() {
+            let context = |e: io::Error| io::Error::new(e.kind(), format!("unable to load history file {path:?}: {e}"));
+            common.load_history(path).map_err(context)?;
+            let file = std::fs::OpenOptions::new().create(true).append(true).open(path).map_err(context)?;
+            *common.history_file.lock().unwrap() = Some(file);
+        }
+
+        // Workers serve requests to completion, except for SSE streams, which are handed off to dedicated threads
+        // (see `handle_request`) - so `workers` bounds concurrent requests, not concurrent chat subscribers.
+        let (queue, jobs) = sync_channel(workers * QUEUE_PER_WORKER);
+        let jobs = Arc::new(Mutex::new(jobs));
+        for _ in 0 .. workers {
+            let jobs = Arc::clone(&jobs);
+            std::thread::spawn(move || work(&jobs));
+        }
+
+        let acceptors = listeners.into_iter().map(|listener| {
+            let common = Arc::clone(&common);
+            let queue = queue.clone();
+            std::thread::spawn(move || accept(&common, &listener, &queue, max_connections, tcp_keepalive))
+        }).collect::<Vec<_>>();
+        drop(queue);
+
+        while !ctrl_c::requested() && !common.stop_requested.load(SeqCst) && !acceptors.iter().any(|a| a.is_finished()) {
+            std::thread::sleep(ACCEPT_POLL);
+        }
+
+        // Stop accepting, and have every SSE subscriber send a final event and exit.
+        common.shutdown.store(true, SeqCst);
+        for room in common.rooms.lock().unwrap().values() { room.close() }
+        let mut result = Ok(());
+        for acceptor in acceptors {
+            let r = acceptor.join().unwrap();
+            if result.is_ok() { result = r }
+        }
+
+        let drain_start = Instant::now();
+        while common.connections.load(SeqCst) > 0 && drain_start.elapsed() < DRAIN_TIMEOUT {
+            std::thread::sleep(ACCEPT_POLL);
+        }
+        result
+    }
+}
+
+fn accept(common: &Arc<Common>, listener: &TcpListener, queue: &SyncSender<(TcpStream, ConnectionGuard)>, max_connections: usize, tcp_keepalive: Option<Duration>) -> io::Result<()> {
+    listener.set_nonblocking(true)?; // so we notice `common.shutdown`
+    while !common.shutdown.load(SeqCst) {
+        let stream = match listener.accept() {
+            Ok((stream, peer)) if common.config.admits(peer.ip()) => stream,
+            Ok((stream, peer)) => {
+                // Like being too busy, below.
+                debug!("refused connection from {peer}: --deny-ip or not --allow-ip");
+                let _ = stream.set_nonblocking(true);
+                let _ = Response::new(common, Peer(Some(peer))).send_error(&stream, "403 Forbidden", "Connection: close\r\n");
+                continue;
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => { std::thread::sleep(ACCEPT_POLL); continue },
+            Err(e) => return Err(e),
+        };
+        if common.connections.load(SeqCst) >= max_connections {
+            // Without reading anything - and without blocking, in case the client isn't reading either.
+            let peer = Peer(stream.peer_addr().ok());
+            let _ = stream.set_nonblocking(true);
+            let _ = Response::new(common, peer).send_error(&stream, "503 Service Unavailable", "Connection: close\r\n");
+            continue;
+        }
+        stream.set_nonblocking(false)?; // may be inherited from `listener` on some platforms
+        if let Some(idle) = tcp_keepalive {
+            if let Err(e) = tcp_keepalive::enable(&stream, idle) { warn!("unable to enable TCP keepalive: {e}") }
+        }
+        let connection = ConnectionGuard::new(common);
+        if queue.send((stream, connection)).is_err() { break } // workers gone?
+    }
+    Ok(())
+}
+
+fn work(jobs: &Mutex<Receiver<(TcpStream, ConnectionGuard)>>) {
+    loop {
+        let job = jobs.lock().unwrap().recv();
+        let Ok((stream, connection)) = job else { return };
+        let peer = Peer(stream.peer_addr().ok()); // fails if the client already hung up
+        // A panicking request shouldn't take its worker with it - the pool would shrink with every one, until nothing's left to serve.
+        // The connection is dropped (its guard included) as the panic unwinds, and the panic hook has already reported it.
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handle_request(&connection.0, &stream, peer))) {
+            Ok(result)  => log_connection_error(peer, result),
+            Err(_panic) => error!("panicked handling connection from {peer}"),
+        }
+    }
+}
+
+fn log_connection_error(peer: Peer, result: io::Result<()>) {
+    if let Err(e) = result {
+        match e.kind() {
+            _ if is_timeout(&e)                 => warn!("error handling connection from {peer}: {:?}", io::ErrorKind::TimedOut), // or WouldBlock, so far as the platform's concerned
+            io::ErrorKind::ConnectionAborted    => warn!("error handling connection from {peer}: {:?}", e.kind()),
+            _other                              => error!("error handling connection from {peer}: {e:?}"),
+        }
+    }
+}
+
+/// The remote address of a connection, if known - displayed as `unknown` otherwise.
+#[derive(Clone, Copy)]
+struct Peer(Option<SocketAddr>);
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0 {
+            Some(addr)  => write!(f, "{addr}"),
+            None        => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Ctrl+C / SIGINT / SIGTERM detection, without pulling in any crates.
+pub mod ctrl_c {
+    use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+
+    static REQUESTED : AtomicBool = AtomicBool::new(false);
+
+    pub fn requested() -> bool { REQUESTED.load(SeqCst) }
+
+    #[cfg(unix)] pub fn install() {
+        extern "C" { fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize; }
+        extern "C" fn on_signal(_signum: i32) { REQUESTED.store(true, SeqCst) }
+        const SIGINT  : i32 = 2;
+        const SIGTERM : i32 = 15;
+        unsafe {
+            signal(SIGINT,  on_signal);
+            signal(SIGTERM, on_signal);
+        }
+    }
+
+    #[cfg(windows)] pub fn install() {
+        #[link(name = "kernel32")] extern "system" { fn SetConsoleCtrlHandler(handler: Option<extern "system" fn(u32) -> i32>, add: i32) -> i32; }
+        extern "system" fn on_ctrl(_ctrl_type: u32) -> i32 { REQUESTED.store(true, SeqCst); 1 }
+        unsafe { SetConsoleCtrlHandler(Some(on_ctrl), 1) };
+    }
+
+    #[cfg(not(any(unix, windows)))] pub fn install() {}
+}
+
+/// TCP keepalive with a custom idle time, which `std` doesn't expose - also without pulling in any crates.
+mod tcp_keepalive {
+    use std::io;
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios"))] pub fn enable(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        extern "C" { fn setsockopt(socket: i32, level: i32, name: i32, value: *const i32, len: u32) -> i32; }
+        const SOL_SOCKET    : i32 = if cfg!(any(target_os = "linux", target_os = "android")) { 1 } else { 0xFFFF };
+        const SO_KEEPALIVE  : i32 = if cfg!(any(target_os = "linux", target_os = "android")) { 9 } else { 0x8 };
+        const IPPROTO_TCP   : i32 = 6;
+        const TCP_KEEPIDLE  : i32 = if cfg!(any(target_os = "linux", target_os = "android")) { 4 } else { 0x10 }; // `TCP_KEEPALIVE` on Apple platforms
+        let set = |level, name, value: i32| match unsafe { setsockopt(stream.as_raw_fd(), level, name, &value, 4) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
+        set(SOL_SOCKET, SO_KEEPALIVE, 1)?;
+        set(IPPROTO_TCP, TCP_KEEPIDLE, idle.as_secs().clamp(1, i32::MAX as u64) as i32)
+    }
+
+    #[cfg(windows)] pub fn enable(stream: &TcpStream, idle: Duration) -> io::Result<()> {
+        use std::os::windows::io::AsRawSocket;
+        #[link(name = "ws2_32")] extern "system" { fn setsockopt(socket: usize, level: i32, name: i32, value: *const i32, len: i32) -> i32; }
+        const SOL_SOCKET    : i32 = 0xFFFF;
+        const SO_KEEPALIVE  : i32 = 0x8;
+        const IPPROTO_TCP   : i32 = 6;
+        const TCP_KEEPIDLE  : i32 = 3; // Windows 10 1709+
+        let set = |level, name, value: i32| match unsafe { setsockopt(stream.as_raw_socket() as usize, level, name, &value, 4) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
+        set(SOL_SOCKET, SO_KEEPALIVE, 1)?;
+        set(IPPROTO_TCP, TCP_KEEPIDLE, idle.as_secs().clamp(1, i32::MAX as u64) as i32)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", windows)))] pub fn enable(_stream: &TcpStream, _idle: Duration) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "not implemented for this platform"))
+    }
+}
+
+/// Why a request was refused - before its route could respond, or by the route itself - or the IO error that stopped it.
+///
+/// Each maps to an error status (and any headers it requires) in one place, [`Response::fail`], and always closes the connection: after
+/// a malformed request or a body we won't read, there's no telling where the next request would start.
+enum HandlerError {
+    /// Malformed, for the reason given.
+    BadRequest(&'static str),
+    /// Missing or wrong credentials, challenged with this `WWW-Authenticate` value.
+    Unauthorized(&'static str),
+    /// Not from an address allowed to make the request.
+    Forbidden,
+    /// No such route, file, or room - or a route that's disabled, like the admin routes without `--admin-token`.
+    NotFound,
+    /// The path is routed, but only for these methods - an `Allow` value.
+    MethodNotAllowed(String),
+    Timeout,
+    /// A POST with neither `Content-Length` nor chunked `Transfer-Encoding`.
+    LengthRequired,
+    /// The request line and headers exceed `Config::max_request` (this many bytes).
+    HeadersTooLarge(usize),
+    /// There are more than `Config::max_headers` (this many) header fields.
+    TooManyHeaders(usize),
+    /// The body is over a limit, described for the client.
+    PayloadTooLarge(String),
+    /// An `Expect` other than `100-continue`, the only expectation we know how to meet.
+    ExpectationFailed,
+    /// HTTP/0.9, which is answered anyways - as HTTP/1.x.
+    UpgradeRequired,
+    /// `/ws` without a valid WebSocket handshake.
+    WebSocketRequired,
+    /// Rate limited, until this many seconds from now.
+    TooManyRequests(u64),
+    VersionNotSupported,
+    /// A method, transfer coding, or feature we don't implement.
+    NotImplemented(&'static str),
+    /// Shutting down, or out of rooms.
+    ServiceUnavailable,
+    Io(io::Error),
+}
+
+impl From<io::Error> for HandlerError {
+    fn from(e: io::Error) -> Self { Self::Io(e) }
+}
+
+impl HandlerError {
+    fn status(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_)         => "400 Bad Request",
+            Self::Unauthorized(_)       => "401 Unauthorized",
+            Self::Forbidden             => "403 Forbidden",
+            Self::NotFound              => "404 Not Found",
+            Self::MethodNotAllowed(_)   => "405 Method Not Allowed",
+            Self::Timeout               => "408 Request Timeout",
+            Self::LengthRequired        => "411 Length Required",
+            Self::HeadersTooLarge(_)    => "431 Request Header Fields Too Large", // not 413 (RFC 6585 § 5): that's about bodies
+            Self::TooManyHeaders(_)     => "431 Request Header Fields Too Large",
+            Self::PayloadTooLarge(_)    => "413 Payload Too Large",
+            Self::ExpectationFailed     => "417 Expectation Failed",
+            Self::UpgradeRequired       => "426 Upgrade Required",
+            Self::WebSocketRequired     => "426 Upgrade Required",
+            Self::TooManyRequests(_)    => "429 Too Many Requests",
+            Self::VersionNotSupported   => "505 HTTP Version Not Supported",
+            Self::NotImplemented(_)     => "501 Not Implemented",
+            Self::ServiceUnavailable    => "503 Service Unavailable",
+            Self::Io(_)                 => "500 Internal Server Error", // never sent - see `Response::fail`
+        }
+    }
+
+    fn headers(&self) -> String {
+        match self {
+            Self::Unauthorized(challenge)   => format!("WWW-Authenticate: {challenge}\r\n"),
+            Self::MethodNotAllowed(allow)   => format!("Allow: {allow}\r\n"),
+            Self::UpgradeRequired           => String::from("Upgrade: HTTP/1.1, HTTP/1.0\r\n"),
+            Self::WebSocketRequired         => String::from("Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n"),
+            Self::TooManyRequests(secs)     => format!("Retry-After: {secs}\r\n"),
+            _                               => String::new(),
+        }
+    }
+}
+
+impl Display for HandlerError {
+    /// What went wrong, for the response body and logs - empty when the status says it all.
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::BadRequest(reason)        => write!(f, "{reason}"),
+            Self::HeadersTooLarge(limit)    => write!(f, "Request line and headers exceed {limit} bytes"),
+            Self::TooManyHeaders(limit)     => write!(f, "More than {limit} header fields"),
+            Self::PayloadTooLarge(detail)   => write!(f, "{detail}"),
+            Self::ExpectationFailed         => write!(f, "Only the 100-continue expectation is supported"),
+            Self::NotImplemented(feature)   => write!(f, "{feature} is not supported"),
+            Self::Io(e)                     => write!(f, "{e}"),
+            _                               => Ok(()),
+        }
+    }
+}
+
+/// The headers of a request that affect how it's handled, as interpreted by [`parse_headers`].
+#[derive(Default)]
+struct RequestHeaders<'p> {
+    content_length:         Option<usize>,
+    chunked:                bool,
+    expect_continue:        bool,
+    last_event_id:          Option<u64>,
+    connection_close:       bool,
+    connection_keep_alive:  bool,
+    connection_upgrade:     bool,
+    upgrade_websocket:      bool,
+    websocket_key:          Option<&'p str>,
+    websocket_version:      Option<&'p str>,
+    accept_gzip:            bool,
+    range:                  Option<&'p str>,
+    if_none_match:          Option<&'p str>,
+    if_modified_since:      Option<u64>,
+    authorization:          Option<&'p str>,
+    json_body:              bool,
+    chat_user:              Option<String>,
+    host:                   Option<&'p str>,
+}
+
+/// The request line and headers of an HTTP/1.x request, split apart but otherwise uninterpreted.
+struct ParsedRequest {
+    method:         String,
+    target:         String,                 // path and query, still percent-encoded
+    version:        String,                 // e.g. "HTTP/1.1" - empty for request lines without one
+    headers:        Vec<(String, String)>,  // in order, duplicates included
+    body_offset:    usize,                  // index of the first byte after the headers' CRLFCRLF
+}
+
+enum ParseError {
+    /// No CRLFCRLF yet - read more and try again.
+    Incomplete,
+    BadRequestLine,
+    /// A header line that isn't `name: value`, or contains control characters - see [RFC 7230 § 3.2](https://datatracker.ietf.org/doc/html/rfc7230#section-3.2).
+    BadHeader,
+    /// More header lines than the limit given to [`parse_request`].
+    TooManyHeaders,
+}
+
+/// Whether `b` may appear in a token, such as a method or header field name ([RFC 7230 § 3.2.6](https://datatracker.ietf.org/doc/html/rfc7230#section-3.2.6)).
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Split the head of the request at the start of `buf` - no IO, and nothing beyond the syntax (and `max_headers`) is validated.
+///
+/// Control characters (other than tabs in header values) are rejected, so nothing parsed can smuggle line breaks into logs or responses.
+/// Lines are then decoded lossily as UTF-8.
+fn parse_request(buf: &[u8], max_headers: usize) -> Result<ParsedRequest, ParseError> {
+    let crlfcrlf_index = buf.windows(4).position(|w| w == b"\r\n\r\n").ok_or(ParseError::Incomplete)?;
+    let head = String::from_utf8_lossy(&buf[..crlfcrlf_index]);
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let Some((method, (target, version))) = request_line.split_once(" ").map(|(m, t_v)| (m, t_v.split_once(" ").unwrap_or((t_v, "")))) else {
+        return Err(ParseError::BadRequestLine);
+    };
+    if method.is_empty() || !method.bytes().all(is_tchar) { return Err(ParseError::BadRequestLine) }
+    if request_line.bytes().any(|b| b.is_ascii_control()) { return Err(ParseError::BadRequestLine) }
+
+    let mut headers = Vec::new();
+    for line in lines {
+        if headers.len() == max_headers { return Err(ParseError::TooManyHeaders) } // before spending any more time on them
+        let Some((name, value)) = line.split_once(':') else { return Err(ParseError::BadHeader) };
+        if name.is_empty() || !name.bytes().all(is_tchar) { return Err(ParseError::BadHeader) } // including whitespace before the `:`, or obsolete line folding
+        if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') { return Err(ParseError::BadHeader) }
+        headers.push((name.to_string(), value.trim_matches([' ', '\t']).to_string())); // values may have optional whitespace on either side
+    }
+
+    Ok(ParsedRequest {
+        method:         method.to_string(),
+        target:         target.to_string(),
+        version:        version.to_string(),
+        headers,
+        body_offset:    crlfcrlf_index + 4,
+    })
+}
+
+/// The origin-form (`/path?query`) of a request `target`, which is already in that form unless it's absolute (`http://host/path?query`).
+///
+/// Origin servers must accept absolute-form targets ([RFC 7230 § 5.3.2](https://datatracker.ietf.org/doc/html/rfc7230#section-5.3.2)),
+/// although they're meant for proxies - only the path and query are routed on, whatever the authority says.
+fn origin_form(target: &str) -> Cow<'_, str> {
+    let Some((scheme, rest)) = target.split_once("://") else { return target.into() };
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") { return target.into() }
+    match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('/')   => rest[i..].into(),
+        Some(i)                                 => format!("/{}", &rest[i..]).into(),
+        None                                    => "/".into(),
+    }
+}
+
+/// The authority (`host:port`) of an absolute-form request `target` - see [`origin_form`].
+fn target_authority(target: &str) -> Option<&str> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") { return None }
+    rest.split(['/', '?']).next()
+}
+
+/// A `Host` header (or absolute-form authority) without any port or userinfo, and lowercased - as `Config::vhosts` are named.
+fn normalize_host(host: &str) -> String {
+    let host = host.rsplit_once('@').map_or(host, |(_userinfo, host)| host);
+    let host = match host.find(']') {
+        Some(end) if host.starts_with('[') => &host[..=end], // an IPv6 literal, with colons of its own
+        _ => host.split(':').next().unwrap_or_default(),
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The response version for a request's `version`, or why it can't be served.
+fn response_version(version: &str) -> Result<&'static str, HandlerError> {
+    match version {
+        "HTTP/0.9"                      => Err(HandlerError::UpgradeRequired),
+        "HTTP/1.0"                      => Ok("HTTP/1.0"),
+        v if v.starts_with("HTTP/1.")   => Ok("HTTP/1.1"),
+        // Including the HTTP/2 connection preface ("PRI * HTTP/2.0"), sent by clients assuming prior knowledge of h2c support.
+        v if v.starts_with("HTTP/2") || v.starts_with("HTTP/3") => Err(HandlerError::VersionNotSupported),
+        v if v.starts_with("HTTP/")     => Ok("HTTP/1.1"),
+        _                               => Err(HandlerError::VersionNotSupported),
+    }
+}
+
+fn parse_headers(headers: &[(String, String)]) -> Result<RequestHeaders<'_>, HandlerError> {
+    let mut h = RequestHeaders::default();
+    for (name, value) in headers {
+        // Field names are case-insensitive.
+        let (name, value) = (&**name, &**value);
+        match name {
+            _ if name.eq_ignore_ascii_case("Content-Length") => {
+                // Disagreeing about where the body ends is how requests get smuggled past proxies - refuse to guess.
+                let length : usize = match value.parse() {
+                    Ok(n) if value.bytes().all(|b| b.is_ascii_digit()) && h.content_length.is_none_or(|prev| prev == n) => n,
+                    _ => return Err(HandlerError::BadRequest("Content-Length is malformed, or conflicts with another")),
+                };
+                h.content_length = Some(length);
+            },
+            _ if name.eq_ignore_ascii_case("Transfer-Encoding") => {
+                // Chunked is the only transfer coding we decode - so no "gzip, chunked" either.
+                if h.chunked { return Err(HandlerError::BadRequest("Transfer-Encoding is given more than once")) }
+                if !value.eq_ignore_ascii_case("chunked") { return Err(HandlerError::NotImplemented("Transfer-Encoding other than chunked")) }
+                h.chunked = true;
+            },
+            _ if name.eq_ignore_ascii_case("Host")          => {
+                if h.host.is_some() { return Err(HandlerError::BadRequest("Host is given more than once")) } // RFC 7230 § 5.4
+                h.host = Some(value);
+            },
+            _ if name.eq_ignore_ascii_case("Expect")        => {
+                // RFC 7231 § 5.1.1: an expectation we can't meet is refused before the body is read, rather than ignored.
+                if !value.eq_ignore_ascii_case("100-continue") { return Err(HandlerError::ExpectationFailed) }
+                h.expect_continue = true;
+            },
+            _ if name.eq_ignore_ascii_case("Last-Event-ID") => h.last_event_id = value.parse::<u64>().ok(),
+            _ if name.eq_ignore_ascii_case("Accept-Encoding") => h.accept_gzip = accepts_gzip(value),
+            _ if name.eq_ignore_ascii_case("Range")         => h.range = Some(value),
+            _ if name.eq_ignore_ascii_case("If-None-Match") => h.if_none_match = Some(value),
+            _ if name.eq_ignore_ascii_case("If-Modified-Since") => h.if_modified_since = parse_http_date(value),
+            _ if name.eq_ignore_ascii_case("Authorization") => h.authorization = Some(value),
+            _ if name.eq_ignore_ascii_case("X-Chat-User")   => h.chat_user = sanitize_user_name(value),
+            _ if name.eq_ignore_ascii_case("Content-Type")  => h.json_body = value.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("application/json"),
+            _ if name.eq_ignore_ascii_case("Connection")    => {
+                for option in value.split(',').map(str::trim) {
+                    h.connection_close      |= option.eq_ignore_ascii_case("close");
+                    h.connection_keep_alive |= option.eq_ignore_ascii_case("keep-alive");
+                    h.connection_upgrade    |= option.eq_ignore_ascii_case("upgrade");
+                }
+            },
+            _ if name.eq_ignore_ascii_case("Upgrade")       => h.upgrade_websocket = value.split(',').any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket")),
+            _ if name.eq_ignore_ascii_case("Sec-WebSocket-Key")     => h.websocket_key = Some(value),
+            _ if name.eq_ignore_ascii_case("Sec-WebSocket-Version") => h.websocket_version = Some(value),
+            _unknown => {},
+        }
+    }
+    if h.chunked && h.content_length.is_some() { return Err(HandlerError::BadRequest("Content-Length and Transfer-Encoding are both given")) }
+    Ok(h)
+}
+
+fn handle_request(common: &Arc<Common>, stream: &TcpStream, peer: Peer) -> io::Result<()> {
+    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Resources_and_specifications
+    // https://datatracker.ietf.org/doc/html/rfc7230    Hypertext Transfer Protocol (HTTP/1.1): Message Syntax and Routing
+    // https://datatracker.ietf.org/doc/html/rfc7231    Hypertext Transfer Protocol (HTTP/1.1): Semantics and Content
+    // https://datatracker.ietf.org/doc/html/rfc6585    Additional HTTP Status Codes
+
+    stream.set_read_timeout(common.config.read_timeout)?;
+    stream.set_write_timeout(common.config.write_timeout)?;
+    // Chat is all small writes - responses, events, pings - that Nagle's algorithm would hold back waiting for ACKs.
+    if let Err(e) = stream.set_nodelay(true) { warn!("unable to set TCP_NODELAY for {peer}: {e}") }
+
+    let max_request = common.config.max_request;
+    let mut request = vec![0u8; MIN_REQUEST.min(max_request)];
+    let mut read = 0;          // bytes of `request` received so far - may include pipelined requests after the current one
+    let mut searched = 0usize; // bytes of `request` already searched for the end of the current request's headers
+    let mut served = 0;        // requests already answered on this connection
+    let read_some = |buf: &mut [u8]| -> io::Result<usize> {
+        let this_read = read_retrying(stream, buf)?;
+        common.metrics.read_bytes.fetch_add(this_read as u64, SeqCst);
+        Ok(this_read)
+    };
+
+    loop {
+        let mut response = Response::new(common, peer);
+        if served == 0 && common.config.allow_raw {
+            let greeting = request[..read].iter().position(|&b| b == b'\n').and_then(|end| Some((raw_greeting(&request[..end])?, end + 1)));
+            if let Some(((room, user), end)) = greeting { return raw_session(common, stream, peer, room, user, request[end..read].to_vec()) }
+        }
+        let crlfcrlf_search_start = searched.saturating_sub(3);
+        searched = read;
+        if !request[crlfcrlf_search_start..read].windows(4).any(|w| w == b"\r\n\r\n") {
+            if read == request.len() && !grow(&mut request, max_request) { return response.fail(stream, HandlerError::HeadersTooLarge(max_request)) }
+            let this_read = match read_some(&mut request[read..]) {
+                Err(e) if is_timeout(&e) && read == 0 && served > 0 => return Ok(()), // idle keep-alive connection
+                Err(e) if is_timeout(&e) => return response.fail(stream, HandlerError::Timeout),
+                other => other?,
+            };
+            if this_read == 0 && read == 0 && served > 0 { return Ok(()) } // client closed a keep-alive connection between requests
+            if this_read == 0 { return response.fail(stream, HandlerError::BadRequest("Connection closed mid-request")) }
+            if read == 0 { response.started = Instant::now() } // don't count time spent idle between keep-alive requests
+            read += this_read;
+            continue;
+        }
+        common.metrics.requests.fetch_add(1, SeqCst);
+
+        let parsed = parse_request(&request[..read], common.config.max_headers);
+        let crlf_index = request[..read].windows(2).position(|w| w == b"\r\n").unwrap();
+        debug!("request from {peer}: {:?}", String::from_utf8_lossy(&request[..crlf_index]));
+        let parsed = match parsed {
+            Ok(parsed)                      => parsed,
+            Err(ParseError::Incomplete)     => unreachable!("CRLFCRLF was found above"),
+            Err(ParseError::BadRequestLine) => return response.fail(stream, HandlerError::BadRequest("Malformed request line")),
+            Err(ParseError::BadHeader)      => return response.fail(stream, HandlerError::BadRequest("Malformed header line")),
+            Err(ParseError::TooManyHeaders) => return response.fail(stream, HandlerError::TooManyHeaders(common.config.max_headers)),
+        };
+        let target = origin_form(&parsed.target);
+        let (method, url, version) = (&*parsed.method, &*target, &*parsed.version);
+        response.method = method.to_string();
+        response.path   = url.split('?').next().unwrap_or_default().to_string();
+        response.version = match response_version(version) {
+            Ok(version) => version,
+            Err(e)      => return response.fail(stream, e),
+        };
+        let response_version = response.version;
+        // CONNECT's authority-form ("host:port") is refused by the router, with a 501.
+        if !url.starts_with('/') && url != "*" && method != "CONNECT" { return response.fail(stream, HandlerError::BadRequest("Malformed request target")) }
+        let RequestHeaders {
+            content_length, chunked, expect_continue, last_event_id, connection_close, connection_keep_alive, connection_upgrade,
+            upgrade_websocket, websocket_key, websocket_version, accept_gzip, range, if_none_match, if_modified_since, authorization, json_body,
+            chat_user, host,
+        } = match parse_headers(&parsed.headers) {
+            Ok(headers) => headers,
+            Err(e)      => return response.fail(stream, e),
+        };
+        // Even with an absolute-form target - whose authority takes precedence anyways.  HTTP/1.0 clients predate the requirement.
+        if host.is_none() && response_version == "HTTP/1.1" { return response.fail(stream, HandlerError::BadRequest("HTTP/1.1 requests must have a Host header")) }
+        // Whatever the method, and before adding it to anything: a huge `Content-Length` would overflow - or wrap, and misframe what's pipelined after.
+        let request_end = content_length.map_or(Some(parsed.body_offset), |length| parsed.body_offset.checked_add(length)).filter(|&end| end <= max_request);
+        let Some(mut request_end) = request_end else { // unless chunked
+            return response.fail(stream, HandlerError::PayloadTooLarge(format!("Request, including headers, exceeds {max_request} bytes")));
+        };
+
+        // HTTP/1.1 connections persist unless asked not to, HTTP/1.0 connections only if asked to.
+        // We only decode chunked bodies for routes that want a body, and can't find the end of one we don't decode - POST routes
+        // that don't want one close the connection instead (see `admin_done`, and below).
+        // Likewise, only routes that want a body send "100 Continue", and otherwise we can't know if the client will send it anyways.
+        let host = target_authority(&parsed.target).or(host).map(normalize_host);
+        let keep_alive = !connection_close && (connection_keep_alive || response_version != "HTTP/1.0") && (!(chunked || expect_continue) || method == "POST");
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+
+        let range = range.filter(|_| method == "GET"); // meaningless for anything else
+        let accept_gzip = accept_gzip && range.is_none(); // ranges of the unencoded body are less surprising
+
+        let mut w = BufWriter::new(stream);
+
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let Some(path) = percent_decode(path) else { return response.fail(&mut w, HandlerError::BadRequest("Malformed percent escape in path")) };
+        let path = normalize_path(&path);
+        let path = &*path;
+        let query = parse_query(query);
+        let chat_room = match path.strip_prefix("/chat") {
+            Some("")    => Some(query_value(&query, "room").unwrap_or("general")),
+            Some(rest)  => rest.strip_prefix('/'),
+            None        => None,
+        }.filter(|room| is_valid_room_name(room));
+        let chat_user = chat_user.or_else(|| query_value(&query, "user").and_then(sanitize_user_name));
+
+        // Load balancers polling /health don't know the password, and there's nothing to protect there.
+        // Admins have their own token instead, which takes the place of the password in `Authorization`.
+        let admin = common.config.admin_token.is_some() && path.starts_with("/admin/");
+        if let Some(auth) = common.config.auth.as_deref().filter(|_| path != "/health" && !admin) {
+            let credentials = authorization.and_then(|a| a.split_once(' ')).filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Basic")).and_then(|(_, b64)| base64_decode(b64.trim()));
+            if !credentials.is_some_and(|credentials| constant_time_eq(&credentials, auth.as_bytes())) {
+                return response.fail(&mut w, HandlerError::Unauthorized("Basic realm=\"chat\", charset=\"UTF-8\""));
+            }
+        }
+
+        if chat_room.is_some() { response.cors_origin = common.config.cors_origin.as_deref() }
+        let mut ex = Exchange {
+            common, peer, stream, w, response,
+            method, path, query: &query, host: host.as_deref(), chat_room, chat_user, keep_alive, connection,
+            content_length, chunked, expect_continue, json_body, last_event_id, accept_gzip, range, if_none_match, if_modified_since,
+            upgrade_websocket, connection_upgrade, websocket_key, websocket_version, authorization,
+            buf: &mut request, read, request_end,
+        };
+        let persist = common.router.dispatch(&mut ex)?;
+        ex.w.flush()?;
+        (read, request_end) = (ex.read, ex.request_end);
+        drop(ex);
+        // Whatever followed a chunked body nothing decoded would be its chunks, misread as the next request - and perhaps not as a proxy
+        // in front of us read them.  Decoding moves `request_end` past the (at least 5 byte) last chunk.
+        let undecoded = chunked && request_end == parsed.body_offset;
+        if !persist || undecoded { return Ok(()) }
+        served += 1;
+
+        // Discard this request - including any body the route didn't read - so `request` starts with whatever was pipelined after it.
+        if request_end <= read {
+            request.copy_within(request_end..read, 0);
+            read -= request_end;
+        } else {
+            let mut unread = request_end - read;
+            while unread > 0 {
+                let chunk = unread.min(request.len());
+                let this_read = read_some(&mut request[..chunk])?;
+                if this_read == 0 { return Ok(()) }
+                unread -= this_read;
+            }
+            read = 0;
+        }
+        searched = 0;
+    }
+}
+
+/// Everything a route's [`Handler`] has to work with: the parsed request, the connection (to read a body, or hand off to a stream), and somewhere to respond.
+struct Exchange<'e> {
+    common:             &'e Arc<Common>,
+    peer:               Peer,
+    stream:             &'e TcpStream,
+    w:                  BufWriter<&'e TcpStream>,
+    response:           Response<'e>,
+
+    method:             &'e str,
+    path:               &'e str,                    // percent-decoded, without the query
+    query:              &'e [(String, String)],
+    host:               Option<&'e str>,            // from an absolute-form target, or `Host` - see `normalize_host`
+    chat_room:          Option<&'e str>,            // for `/chat`, `/chat/{room}`, or `/chat?room={room}` with a valid room name
+    chat_user:          Option<String>,             // from `X-Chat-User` or `?user=`, sanitized
+    keep_alive:         bool,                       // whether the connection may persist after a successful response
+    connection:         &'static str,               // `Connection` header value matching `keep_alive`
+
+    content_length:     Option<usize>,
+    chunked:            bool,
+    expect_continue:    bool,
+    json_body:          bool,                       // `Content-Type: application/json`
+    last_event_id:      Option<u64>,
+    accept_gzip:        bool,                       // and no `range`
+    range:              Option<&'e str>,            // only for GETs
+    if_none_match:      Option<&'e str>,
+    if_modified_since:  Option<u64>,                // Unix seconds
+    upgrade_websocket:  bool,
+    connection_upgrade: bool,
+    websocket_key:      Option<&'e str>,
+    websocket_version:  Option<&'e str>,
+    authorization:      Option<&'e str>,
+
+    buf:                &'e mut Vec<u8>,            // the connection's read buffer, starting with this request - see `grow`
+    read:               usize,                      // bytes of `buf` received so far
+    request_end:        usize,                      // end of this request in `buf`, including any body - updated once a chunked body is decoded
+}
+
+impl Exchange<'_> {
+    fn send(&mut self, status: &str, headers: &str, body: &[u8]) -> io::Result<()> {
+        self.response.send(&mut self.w, status, headers, body)
+    }
+
+    /// Refuse the request - see [`Response::fail`] - closing the connection.
+    fn fail(&mut self, e: HandlerError) -> io::Result<bool> {
+        self.response.fail(&mut self.w, e)?;
+        Ok(false)
+    }
+
+    /// Read more of the request into `buf`, returning how many bytes were read (0 at EOF).
+    fn read_some(&mut self) -> io::Result<usize> {
+        let this_read = read_retrying(self.stream, &mut self.buf[self.read..])?;
+        self.common.metrics.read_bytes.fetch_add(this_read as u64, SeqCst);
+        self.read += this_read;
+        Ok(this_read)
+    }
+}
+
+/// Responds to one request, returning whether the connection can persist afterwards.
+///
+/// Responses without a Content-Length (other than 204s and HEADs) can only be delimited by closing the connection.
+type Handler = Box<dyn Fn(&mut Exchange) -> io::Result<bool> + Send + Sync>;
+
+/// How a [`Router`] entry matches requests.
+enum Route {
+    /// The percent-decoded path, exactly.
+    Exact(&'static str),
+    /// Any path naming a valid chat room - see [`Exchange::chat_room`].
+    ChatRoom,
+}
+
+impl Route {
+    fn matches(&self, ex: &Exchange) -> bool {
+        match self {
+            Route::Exact(path)  => ex.path == *path,
+            Route::ChatRoom     => ex.chat_room.is_some(),
+        }
+    }
+}
+
+/// Which [`Handler`] serves each method of each route.  Unregistered methods get a 405 listing the registered ones (or a 204 listing them,
+/// for `OPTIONS`), and requests matching no route at all go to the fallback.
+struct Router {
+    routes:     Vec<(Route, &'static str, Handler)>,
+    fallback:   Handler,
+}
+
+impl Router {
+    fn new(fallback: impl Fn(&mut Exchange) -> io::Result<bool> + Send + Sync + 'static) -> Self {
+        Self { routes: Vec::new(), fallback: Box::new(fallback) }
+    }
+
+    /// Serve `method` requests matching `route` with `handler` - unless an earlier registration already does.
+    ///
+    /// `GET` handlers serve `HEAD` too, unless it's registered separately - [`Response::send`] drops the body.
+    fn add(&mut self, route: Route, method: &'static str, handler: impl Fn(&mut Exchange) -> io::Result<bool> + Send + Sync + 'static) -> &mut Self {
+        self.routes.push((route, method, Box::new(handler)));
+        self
+    }
+
+    fn dispatch(&self, ex: &mut Exchange) -> io::Result<bool> {
+        // Methods we've never heard of are 501s (RFC 7231 § 4.1), rather than 405s from every route that hasn't either.
+        if !KNOWN_METHODS.contains(&ex.method) { return ex.fail(HandlerError::NotImplemented("The request method")) }
+        // We're no proxy to tunnel through.
+        if ex.method == "CONNECT" { return ex.fail(HandlerError::NotImplemented("CONNECT")) }
+        // Nor will we echo requests back, whatever's registered: that would let cross-site tracing read cookies and credentials scripts can't.
+        let trace = ex.method == "TRACE";
+
+        // `OPTIONS *` asks about the server as a whole, rather than any one route.
+        let asterisk = ex.path == "*";
+        if asterisk && ex.method != "OPTIONS" { return ex.fail(HandlerError::BadRequest("Only OPTIONS applies to *")) }
+
+        let mut allow = Vec::new();
+        for (route, method, handler) in &self.routes {
+            if !asterisk && !route.matches(ex) { continue }
+            if !asterisk && !trace && (*method == ex.method || (*method, ex.method) == ("GET", "HEAD")) { return handler(ex) }
+            if !allow.contains(method) && *method != "TRACE" { allow.push(*method) }
+            if !allow.contains(&"HEAD") && *method == "GET" { allow.push("HEAD") }
+        }
+        if allow.is_empty() { return (self.fallback)(ex) } // a 404 for TRACE too, which `static_file` only serves GET and HEAD
+        if !allow.is_empty() && !allow.contains(&"OPTIONS") { allow.push("OPTIONS") }
+        let allow = allow.join(", ");
+
+        match ex.method {
+            "OPTIONS" => {
+                ex.send("204 No Content", &format!("Allow: {allow}\r\nConnection: {}\r\n", ex.connection), b"")?;
+                Ok(ex.keep_alive)
+            },
+            _ => ex.fail(HandlerError::MethodNotAllowed(allow)),
+        }
+    }
+}
+
+impl Default for Router {
+    /// The built-in routes, with `--static-dir` (if any) serving everything else.
+    fn default() -> Self {
+        let mut router = Router::new(static_file);
+        router
+            .add(Route::Exact("/"),                       "GET",     index)
+            .add(Route::Exact("/health"),                 "GET",     health)
+            .add(Route::Exact("/users"),                  "GET",     users)
+            .add(Route::Exact("/metrics"),                "GET",     metrics)
+            .add(Route::Exact("/ws"),                     "GET",     chat_websocket)
+            .add(Route::Exact("/favicon.ico"),            "GET",     favicon)
+            .add(Route::Exact("/robots.txt"),             "GET",     robots)
+            .add(Route::Exact("/admin/clear"),            "POST",    admin_clear)
+            .add(Route::Exact("/admin/kick"),             "POST",    admin_kick)
+            .add(Route::Exact("/admin/shutdown"),         "POST",    admin_shutdown)
+            .add(Route::Exact("/admin/subscribers"),      "GET",     admin_subscribers)
+            .add(Route::Exact("/chat/messages"),          "GET",     chat_messages)
+            .add(Route::Exact("/chat/stream.ndjson"),     "GET",     chat_ndjson)
+            .add(Route::ChatRoom,                         "GET",     chat_stream)
+            .add(Route::ChatRoom,                         "POST",    chat_post)
+            .add(Route::ChatRoom,                         "OPTIONS", chat_options);
+        router
+    }
+}
+
+/// `GET /` - the chat page itself.
+fn index(ex: &mut Exchange) -> io::Result<bool> {
+    static INDEX_HTML_GZ : OnceLock<Vec<u8>> = OnceLock::new();
+    let connection = ex.connection;
+    let config = &ex.common.config;
+    let vhost = ex.host.and_then(|host| config.vhosts.iter().find(|(name, _)| name == host)).map(|(_, path)| path.as_path());
+    let from_disk = vhost.or(config.index_file.as_deref()).and_then(|path| {
+        let path = if path.is_dir() { Cow::Owned(path.join("index.html")) } else { Cow::Borrowed(path) };
+        let read = std::fs::read(&path).and_then(|html| Ok((html, std::fs::metadata(&path)?.modified()?)));
+        read.map_err(|e| warn!("unable to read {path:?}, serving the embedded page instead: {e}")).ok()
+    });
+    let (index_html, modified) : (Cow<[u8]>, u64) = match from_disk {
+        Some((html, modified))  => (html.into(), unix_millis(modified) / 1000),
+        None                    => (include_str!("index.html").as_bytes().into(), ex.common.started), // it can't have changed since we started
+    };
+    let etag = entity_tag(&index_html);
+    let last_modified = http_date(UNIX_EPOCH + Duration::from_secs(modified));
+    // `If-Modified-Since` only counts without `If-None-Match` (RFC 7232 § 3.3).
+    let not_modified = match ex.if_none_match {
+        Some(tags)  => etag_matches(tags, &etag),
+        None        => ex.if_modified_since.is_some_and(|since| since >= modified),
+    };
+    if not_modified {
+        ex.send("304 Not Modified", &format!("ETag: {etag}\r\nLast-Modified: {last_modified}\r\nVary: Accept-Encoding\r\nConnection: {connection}\r\n"), b"")?;
+        return Ok(ex.keep_alive);
+    }
+    let (index_html, content_encoding) : (Cow<[u8]>, _) = match (ex.accept_gzip, index_html) {
+        (true, Cow::Borrowed(html)) => (INDEX_HTML_GZ.get_or_init(|| gzip::compress(html))[..].into(), "Content-Encoding: gzip\r\n"),
+        (true, Cow::Owned(html))    => (gzip::compress(&html).into(), "Content-Encoding: gzip\r\n"),
+        (false, html)               => (html, ""),
+    };
+    let (status, range_headers, index_html) = byte_range(ex.range, &index_html);
+    let index_html_len = index_html.len();
+
+    let content_type = content_type_for("index.html");
+    let headers = format!("Content-Type: {content_type}\r\n{content_encoding}ETag: {etag}\r\nLast-Modified: {last_modified}\r\nVary: Accept-Encoding\r\n{range_headers}Content-Length: {index_html_len}\r\nConnection: {connection}\r\n");
+    ex.send(status, &headers, index_html)?;
+    Ok(ex.keep_alive)
+}
+
+/// `GET /health` - cheap enough for load balancers to poll: no locks, no subscribing, no rate limiting.
+fn health(ex: &mut Exchange) -> io::Result<bool> {
+    let connection = ex.connection;
+    let (content_type, body) = match query_value(ex.query, "format") {
+        Some("json")    => ("application/json", format!("{{\"status\":\"ok\",\"listeners\":{}}}", ex.common.subscribers.load(SeqCst))),
+        _               => ("text/plain; charset=UTF-8", String::from("ok")),
+    };
+    let body_len = body.len();
+
+    let headers = format!("Cache-Control: no-store\r\nContent-Type: {content_type}\r\nContent-Length: {body_len}\r\nConnection: {connection}\r\n");
+    ex.send("200 OK", &headers, body.as_bytes())?;
+    Ok(ex.keep_alive)
+}
+
+/// `GET /users` - how many are subscribed, either overall or to `?room=`.
+fn users(ex: &mut Exchange) -> io::Result<bool> {
+    let connection = ex.connection;
+    let count = match query_value(ex.query, "room") {
+        Some(room)  => ex.common.existing_room(room).map_or(0, |room| room.subscribers.load(SeqCst)),
+        None        => ex.common.subscribers.load(SeqCst),
+    };
+    let (content_type, body) = match query_value(ex.query, "format") {
+        Some("json")    => ("application/json", format!("{{\"count\":{count}}}")),
+        _               => ("text/plain; charset=UTF-8", count.to_string()),
+    };
+    let body_len = body.len();
+
+    let headers = format!("Cache-Control: no-store\r\nContent-Type: {content_type}\r\nContent-Length: {body_len}\r\nConnection: {connection}\r\n");
+    ex.send("200 OK", &headers, body.as_bytes())?;
+    Ok(ex.keep_alive)
+}
+
+/// `GET /metrics` - for Prometheus to scrape.
+fn metrics(ex: &mut Exchange) -> io::Result<bool> {
+    let connection = ex.connection;
+    let body = ex.common.metrics.prometheus_text(ex.common);
+    let body_len = body.len();
+
+    let headers = format!("Cache-Control: no-store\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {body_len}\r\nConnection: {connection}\r\n");
+    ex.send("200 OK", &headers, body.as_bytes())?;
+    Ok(ex.keep_alive)
+}
+
+/// `GET /favicon.ico`
+fn favicon(ex: &mut Exchange) -> io::Result<bool> {
+    let connection = ex.connection;
+    let favicon_ico = include_bytes!("favicon.ico");
+    let favicon_ico_len = favicon_ico.len();
+
+    let headers = format!("Content-Type: image/x-icon\r\nContent-Length: {favicon_ico_len}\r\nConnection: {connection}\r\n");
+    ex.send("200 OK", &headers, favicon_ico)?;
+    Ok(ex.keep_alive)
+}
+
+/// `GET /robots.txt` - by default, asking crawlers to stay out of the chat entirely.
+fn robots(ex: &mut Exchange) -> io::Result<bool> {
+    let connection = ex.connection;
+    let robots_txt = ex.common.config.robots_txt.as_deref().unwrap_or(include_str!("robots.txt")).as_bytes();
+    let robots_txt_len = robots_txt.len();
+
+    let headers = format!("Content-Type: text/plain; charset=UTF-8\r\nContent-Length: {robots_txt_len}\r\nConnection: {connection}\r\n");
+    ex.send("200 OK", &headers, robots_txt)?;
+    Ok(ex.keep_alive)
+}
+
+/// `GET /ws?room={room}` - a WebSocket chat session, handed off to its own thread.  Text frames from the client are posted like plain text
+/// POSTs, and the room's messages are sent back as text frames of the same JSON objects as `/chat/stream.ndjson` - see [`ndjson`].
+fn chat_websocket(ex: &mut Exchange) -> io::Result<bool> {
+    // https://datatracker.ietf.org/doc/html/rfc6455#section-4.2
+    let common = ex.common;
+    let room = query_value(ex.query, "room").unwrap_or("general");
+    if !is_valid_room_name(room) { return ex.fail(HandlerError::NotFound) }
+    let Some(key) = ex.websocket_key.filter(|_| ex.method == "GET" && ex.upgrade_websocket && ex.connection_upgrade && ex.websocket_version == Some("13")) else {
+        return ex.fail(HandlerError::WebSocketRequired);
+    };
+    let Some(room) = common.room(room) else { return ex.fail(HandlerError::ServiceUnavailable) };
+    let Some(cursor) = room.subscribe_recent(common.config.history) else { return ex.fail(HandlerError::ServiceUnavailable) };
+    let accept = websocket::accept_key(key);
+    ex.send("101 Switching Protocols", &format!("Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n"), b"")?;
+    ex.w.flush()?;
+
+    // Like SSE streams, sessions last indefinitely, so they get their own threads instead of tying up a worker.
+    let stream = ex.stream.try_clone()?;
+    let connection = ConnectionGuard::new(common);
+    let user = ex.chat_user.take().unwrap_or_else(|| String::from("anonymous"));
+    let peer = ex.peer;
+    std::thread::spawn(move || log_connection_error(peer, stream_websocket(&connection.0, &room, &stream, peer, &user, cursor)));
+    Ok(false)
+}
+
+/// `GET /chat` - subscribe to a room's messages as Server-Sent Events, streamed from their own thread.
+fn chat_stream(ex: &mut Exchange) -> io::Result<bool> {
+    let room = ex.chat_room.unwrap_or_default();
+    subscribe(ex, room, Framing::Sse)
+}
+
+/// `GET /chat/stream.ndjson?room={room}` - like `GET /chat`, but as newline delimited JSON for clients without an SSE parser.
+fn chat_ndjson(ex: &mut Exchange) -> io::Result<bool> {
+    let room = query_value(ex.query, "room").unwrap_or("general");
+    if !is_valid_room_name(room) { return ex.fail(HandlerError::NotFound) }
+    ex.response.cors_origin = ex.common.config.cors_origin.as_deref();
+    subscribe(ex, room, Framing::NdJson)
+}
+
+/// `GET /chat/messages?room={room}&since={id}` - poll for the messages after `since` (by default, all those retained) as a JSON array of
+/// [`ndjson`] objects, or a 204 if there aren't any yet.  Rooms only retain `--backlog` messages: if some after `since` are gone, the array
+/// starts with a `{"event":"gap","missed":...}` object counting them.
+fn chat_messages(ex: &mut Exchange) -> io::Result<bool> {
+    let common = ex.common;
+    let room = query_value(ex.query, "room").unwrap_or("general");
+    if !is_valid_room_name(room) { return ex.fail(HandlerError::NotFound) }
+    ex.response.cors_origin = common.config.cors_origin.as_deref();
+    let since = match query_value(ex.query, "since").map(str::parse::<u64>) {
+        None            => None,
+        Some(Ok(id))    => Some(id),
+        Some(Err(_))    => return ex.fail(HandlerError::BadRequest("`since` must be a message id")),
+    };
+
+    let mut items = Vec::new();
+    // Looked up rather than `common.room(...)`, so polling doesn't create rooms - one nobody's used has nothing to poll for anyways.
+    if let Some(room) = common.existing_room(room) {
+        let cursor = match since {
+            Some(id)    => room.subscribe(Some(id)),
+            None        => room.subscribe_recent(usize::MAX),
+        };
+        let Some(mut cursor) = cursor else { return ex.fail(HandlerError::ServiceUnavailable) };
+        loop {
+            match room.recv_timeout(&mut cursor, Duration::ZERO, &AtomicBool::new(false)) {
+                Ok(Recv::Lagged(missed))        => items.push(format!("{{\"event\":\"gap\",\"missed\":{missed}}}")), // messages may follow
+                Ok(Recv::Messages(messages))    => break items.extend(messages.iter().filter(|(id, _)| id.is_some()).map(|(id, msg)| ndjson(*id, msg))),
+                Err(_)                          => break,
+            }
+        }
+    }
+
+    let connection = ex.connection;
+    if items.is_empty() {
+        ex.send("204 No Content", &format!("Cache-Control: no-store\r\nConnection: {connection}\r\n"), b"")?;
+        return Ok(ex.keep_alive);
+    }
+    let body = format!("[{}]\n", items.join(",")).into_bytes();
+    // Unlike the streams, a poll's body is complete before it's sent, so it can be compressed.
+    let (body, content_encoding) = match ex.accept_gzip {
+        true    => (gzip::compress(&body), "Content-Encoding: gzip\r\n"),
+        false   => (body, ""),
+    };
+    let body_len = body.len();
+    let headers = format!("Cache-Control: no-store\r\nContent-Type: application/json; charset=UTF-8\r\n{content_encoding}Vary: Accept-Encoding\r\nContent-Length: {body_len}\r\nConnection: {connection}\r\n");
+    ex.send("200 OK", &headers, &body)?;
+    Ok(ex.keep_alive)
+}
+
+fn subscribe(ex: &mut Exchange, room: &str, framing: Framing) -> io::Result<bool> {
+    let common = ex.common;
+    let content_type = framing.content_type();
+    // Never compressed, whatever `Accept-Encoding` says: gzip buffers, and would hold events back until a block fills up.
+    let headers = format!("Cache-Control: no-store\r\nContent-Type: {content_type}\r\n");
+    if ex.method == "HEAD" { // without subscribing
+        ex.send("200 OK", &format!("{headers}Connection: {}\r\n", ex.connection), b"")?;
+        return Ok(ex.keep_alive);
+    }
+
+    let Some(room) = common.room(room) else { return ex.fail(HandlerError::ServiceUnavailable) };
+    let since = query_value(ex.query, "since").and_then(|since| since.parse::<u64>().ok());
+    let cursor = match ex.last_event_id.or(since) {
+        Some(id)    => room.subscribe(Some(id)),                        // resuming - just what was missed
+        None        => room.subscribe_recent(common.config.history),    // new - catch up on scrollback
+    };
+    let Some(cursor) = cursor else { return ex.fail(HandlerError::ServiceUnavailable) };
+    let new = ex.last_event_id.or(since).is_none();
+    let preamble = match framing {
+        Framing::Sse => {
+            // Jitter reconnects by up to +50% so clients dropped together (e.g. by a restart) don't all return together.
+            let retry = common.config.sse_retry_ms;
+            let retry = retry + RandomState::new().hash_one(()) % (retry / 2 + 1);
+            format!("retry: {retry}\n\n")
+        },
+        Framing::NdJson => String::new(),
+    };
+    ex.send("200 OK", &format!("{headers}Connection: close\r\n"), preamble.as_bytes())?;
+    ex.w.flush()?;
+
+    // Streams last indefinitely, so give them their own thread instead of tying up a worker.
+    let stream = ex.stream.try_clone()?;
+    let connection = ConnectionGuard::new(common);
+    let user = ex.chat_user.take().unwrap_or_else(|| String::from("anonymous"));
+    let peer = ex.peer;
+    std::thread::spawn(move || log_connection_error(peer, stream_chat(&connection.0, &room, &stream, peer, &user, cursor, framing, new)));
+    Ok(false)
+}
+
+/// `POST /chat` - broadcast the body to a room, as plain text or a JSON `{"text": ..., "user": ...}` object.
+fn chat_post(ex: &mut Exchange) -> io::Result<bool> {
+    let common = ex.common;
+    // Without a length, the body could only end at EOF - leaving no way to respond, let alone keep the connection.
+    if !ex.chunked && ex.content_length.is_none() { return ex.fail(HandlerError::LengthRequired) }
+    // Lossy decoding never shrinks a body, so one that's already too long can be refused before reading it.
+    let too_long = || HandlerError::PayloadTooLarge(format!("Message exceeds {} bytes", common.config.max_message));
+    let too_large = || HandlerError::PayloadTooLarge(format!("Request, including headers, exceeds {} bytes", common.config.max_request));
+    if ex.content_length.is_some_and(|n| n > common.config.max_message) { return ex.fail(too_long()) }
+    let Some(room) = ex.chat_room.and_then(|room| common.room(room)) else { return ex.fail(HandlerError::ServiceUnavailable) };
+    if let Some(peer) = ex.peer.0 {
+        let limit = common.rate_limiter.lock().unwrap().take(peer.ip(), common.config.rate_limit, common.config.rate_burst);
+        if let Err(retry_after) = limit {
+            let retry_after = retry_after.as_secs() + 1; // round up
+            return ex.fail(HandlerError::TooManyRequests(retry_after));
+        }
+    }
+
+    let message_start = ex.request_end - ex.content_length.unwrap_or(0);
+    if ex.expect_continue && ex.response.version == "HTTP/1.1" && ex.read == message_start {
+        ex.send("100 Continue", "", b"")?;
+        ex.w.flush()?;
+    }
+    let message : Cow<[u8]> = if ex.chunked {
+        loop {
+            match decode_chunked(&ex.buf[message_start..ex.read]) {
+                Err(()) => return ex.fail(HandlerError::BadRequest("Malformed chunked body")),
+                Ok(Some((message, encoded_len))) => {
+                    ex.request_end = message_start + encoded_len;
+                    break message.into();
+                },
+                Ok(None) if ex.read == ex.buf.len() && !grow(ex.buf, common.config.max_request) => return ex.fail(too_large()),
+                Ok(None) => match ex.read_some() {
+                    Err(e) if is_timeout(&e) => return ex.fail(HandlerError::Timeout),
+                    Ok(0) => return ex.fail(HandlerError::BadRequest("Connection closed mid-body")),
+                    other => { other?; },
+                },
+            }
+        }
+    } else {
+        let message_end = ex.request_end; // within `Config::max_request` - see `handle_request`
+        if message_end > ex.buf.len() { ex.buf.resize(message_end, 0) }
+        while ex.read < message_end {
+            match ex.read_some() {
+                Err(e) if is_timeout(&e) => return ex.fail(HandlerError::Timeout),
+                Ok(0) => return ex.fail(HandlerError::BadRequest("Connection closed mid-body")),
+                other => { other?; },
+            }
+        }
+        // Anything past `message_end` is the next pipelined request, not part of this message.
+        ex.buf[message_start..message_end].into()
+    };
+    if ex.json_body {
+        if message.len() > common.config.max_message { return ex.fail(too_long()) }
+        let Some(json) = std::str::from_utf8(&message).ok().and_then(json::parse) else { return ex.fail(HandlerError::BadRequest("Malformed JSON body")) };
+        // `text` is required, and `user` must be a string if given.
+        let (Some(Json::String(text)), None | Some(Json::String(_))) = (json.get("text"), json.get("user")) else { return ex.fail(HandlerError::BadRequest("`text` and any `user` must be strings")) };
+        let user = json.get("user").and_then(Json::as_str).and_then(sanitize_user_name).or(ex.chat_user.take());
+        let Some(text) = common.filter(text) else { return banned_word(ex) };
+        // Double clicks and retries succeed as if posted again, without subscribers seeing it twice.
+        match common.is_repeat(ex.peer, &room, &text) {
+            true    => debug!("ignored repeated message from {}", ex.peer),
+            false   => common.post_chat(&room, user.as_deref().unwrap_or("anonymous"), &text),
+        }
+    } else {
+        let message = String::from_utf8_lossy(&message).into_owned();
+        if message.len() > common.config.max_message { return ex.fail(too_long()) }
+        let Some(message) = common.filter(&message) else { return banned_word(ex) };
+        match common.is_repeat(ex.peer, &room, &message) {
+            true    => debug!("ignored repeated message from {}", ex.peer),
+            false   => common.post(&room, ex.chat_user.as_deref().unwrap_or("anonymous"), &message),
+        }
+    }
+    ex.send("204 No Content", &format!("Connection: {}\r\n", ex.connection), b"")?;
+    Ok(ex.keep_alive)
+}
+
+/// Refuse a posted message for containing `Config::banned_words` - the body's already been read, so the connection can persist.
+fn banned_word(ex: &mut Exchange) -> io::Result<bool> {
+    debug!("refused message from {}: banned word", ex.peer);
+    let headers = format!("Connection: {}\r\n", ex.connection);
+    ex.response.send_error_detail(&mut ex.w, "422 Unprocessable Entity", &headers, "Message contains a banned word")?;
+    Ok(ex.keep_alive)
+}
+
+/// `OPTIONS /chat` - including CORS preflights.
+fn chat_options(ex: &mut Exchange) -> io::Result<bool> {
+    // `Access-Control-Allow-Origin` itself is added to every chat response by `Response::send`.
+    let preflight = if ex.response.cors_origin.is_some() { "Access-Control-Allow-Methods: GET, POST\r\nAccess-Control-Allow-Headers: Content-Type, Last-Event-ID, X-Chat-User\r\n" } else { "" };
+    ex.send("204 No Content", &format!("Allow: GET, HEAD, POST, OPTIONS\r\n{preflight}Connection: {}\r\n", ex.connection), b"")?;
+    Ok(ex.keep_alive)
+}
+
+/// `POST /admin/clear?room={room}` - empty a room's scrollback.
+fn admin_clear(ex: &mut Exchange) -> io::Result<bool> {
+    let room = match admin_room(ex) {
+        Ok(room)    => room,
+        Err(e)      => return ex.fail(e),
+    };
+    // A room nobody's used has nothing to clear - no need to create it.
+    if let Some(room) = ex.common.existing_room(room) {
+        ex.common.clear(&room);
+        info!("admin {} cleared room {:?}", ex.peer, room.name);
+    }
+    admin_done(ex)
+}
+
+/// `POST /admin/kick?room={room}` - disconnect everyone from a room (although SSE clients will reconnect), and empty its scrollback.
+fn admin_kick(ex: &mut Exchange) -> io::Result<bool> {
+    let room = match admin_room(ex) {
+        Ok(room)    => room,
+        Err(e)      => return ex.fail(e),
+    };
+    ex.common.kick(room);
+    info!("admin {} kicked everyone from room {room:?}", ex.peer);
+    admin_done(ex)
+}
+
+/// `POST /admin/shutdown` - shut down gracefully, as if by Ctrl+C.  Only from this machine.
+fn admin_shutdown(ex: &mut Exchange) -> io::Result<bool> {
+    if let Err(e) = admin_authorized_locally(ex) { return ex.fail(e) }
+    ex.send("202 Accepted", "Content-Length: 0\r\nConnection: close\r\n", b"")?;
+    ex.w.flush()?;
+    info!("admin {} requested shutdown", ex.peer);
+    ex.common.stop_requested.store(true, SeqCst);
+    Ok(false)
+}
+
+/// `GET /admin/subscribers` - every SSE and NDJSON stream, as a JSON array.  Only from this machine.
+fn admin_subscribers(ex: &mut Exchange) -> io::Result<bool> {
+    if let Err(e) = admin_authorized_locally(ex) { return ex.fail(e) }
+    let mut streams = ex.common.streams.lock().unwrap().1.values().cloned().collect::<Vec<_>>();
+    streams.sort_by_key(|stats| stats.started);
+    let streams = streams.iter().map(|stats| {
+        let StreamStats { peer, room, started, messages, bytes } = &**stats;
+        let (peer, room, age_ms, messages, bytes) = (json::quote(&peer.to_string()), json::quote(room), started.elapsed().as_millis(), messages.load(SeqCst), bytes.load(SeqCst));
+        format!("{{\"peer\":{peer},\"room\":{room},\"age_ms\":{age_ms},\"messages\":{messages},\"bytes\":{bytes}}}")
+    }).collect::<Vec<_>>();
+    let body = format!("[{}]\n", streams.join(","));
+    let body_len = body.len();
+    let headers = format!("Cache-Control: no-store\r\nContent-Type: application/json; charset=UTF-8\r\nContent-Length: {body_len}\r\nConnection: {}\r\n", ex.connection);
+    ex.send("200 OK", &headers, body.as_bytes())?;
+    Ok(ex.keep_alive)
+}
+
+/// [`admin_authorized`], and from a loopback address - for the admin routes that are only for local tooling.
+fn admin_authorized_locally(ex: &Exchange) -> Result<(), HandlerError> {
+    admin_authorized(ex)?;
+    if !ex.peer.0.is_some_and(|peer| peer.ip().to_canonical().is_loopback()) { return Err(HandlerError::Forbidden) }
+    Ok(())
+}
+
+/// Whether an admin request gave `--admin-token` - if not, why it's refused.
+fn admin_authorized(ex: &Exchange) -> Result<(), HandlerError> {
+    let Some(token) = ex.common.config.admin_token.as_deref() else { return Err(HandlerError::NotFound) };
+    let bearer = ex.authorization.and_then(|a| a.split_once(' ')).filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer")).map(|(_, t)| t.trim());
+    if !bearer.is_some_and(|bearer| constant_time_eq(bearer.as_bytes(), token.as_bytes())) { return Err(HandlerError::Unauthorized("Bearer realm=\"admin\"")) }
+    Ok(())
+}
+
+/// The room an admin request names with `?room=` (by default `general`), or why it's refused.
+fn admin_room<'e>(ex: &Exchange<'e>) -> Result<&'e str, HandlerError> {
+    admin_authorized(ex)?;
+    let room = query_value(ex.query, "room").unwrap_or("general");
+    if !is_valid_room_name(room) { return Err(HandlerError::NotFound) }
+    Ok(room)
+}
+
+fn admin_done(ex: &mut Exchange) -> io::Result<bool> {
+    // No admin route reads a body, and a chunked one can't be skipped without decoding it - so the connection can't persist.
+    if ex.chunked { (ex.keep_alive, ex.connection) = (false, "close") }
+    ex.send("204 No Content", &format!("Connection: {}\r\n", ex.connection), b"")?;
+    Ok(ex.keep_alive)
+}
+
+/// Anything else - a file from `--static-dir`, if there is one.
+fn static_file(ex: &mut Exchange) -> io::Result<bool> {
+    let connection = ex.connection;
+    let Some(static_dir) = ex.common.config.static_dir.as_deref().filter(|_| matches!(ex.method, "GET" | "HEAD")) else { return ex.fail(HandlerError::NotFound) };
+    let Some(file_path) = static_file_path(static_dir, ex.path) else { return ex.fail(HandlerError::BadRequest("Path has a forbidden segment")) };
+    match std::fs::read(&file_path).map(|body| (entity_tag(&body), body)) {
+        Ok((etag, _)) if ex.if_none_match.is_some_and(|tags| etag_matches(tags, &etag)) => {
+            ex.send("304 Not Modified", &format!("ETag: {etag}\r\nVary: Accept-Encoding\r\nConnection: {connection}\r\n"), b"")?;
+            Ok(ex.keep_alive)
+        },
+        Ok((etag, body)) => {
+            let content_type = content_type_for(ex.path);
+            let (body, content_encoding) = match ex.accept_gzip && is_compressible(content_type) {
+                true    => (gzip::compress(&body), "Content-Encoding: gzip\r\n"),
+                false   => (body, ""),
+            };
+            let (status, range_headers, body) = byte_range(ex.range, &body);
+            let body_len = body.len();
+            let headers = format!("Content-Type: {content_type}\r\n{content_encoding}ETag: {etag}\r\nVary: Accept-Encoding\r\n{range_headers}Content-Length: {body_len}\r\nConnection: {connection}\r\n");
+            ex.send(status, &headers, body)?;
+            Ok(ex.keep_alive)
+        },
+        Err(_) => ex.fail(HandlerError::NotFound),
+    }
+}
+
+/// Double `buf`, up to `max` bytes - or return `false` if it's already that big.
+///
+/// Most requests fit in a `MIN_REQUEST` buffer, so connections only pay for `Config::max_request` when they need it.
+fn grow(buf: &mut Vec<u8>, max: usize) -> bool {
+    if buf.len() >= max { return false }
+    buf.resize((buf.len() * 2).clamp(1, max), 0);
+    true
+}
+
+/// Milliseconds since the Unix epoch - or 0 if `time` is somehow before it.
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// [`Read::read`], retried for as long as it's [`io::ErrorKind::Interrupted`] by a signal (e.g. the `ctrl_c` handler).
+///
+/// `read_exact` and `write_all` (used everywhere else, and by `BufWriter`) already retry, as does [`PatientWriter`] - only bare reads don't.
+fn read_retrying(mut r: impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match r.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            other => return other,
+        }
+    }
+}
+
+/// Whether a read or write failed because `Config::read_timeout` or `write_timeout` elapsed (reported as either kind, depending on platform).
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// Retries writes that time out, up to [`WRITE_RETRIES`] times in a row, so a subscriber whose connection stalls for a moment isn't dropped.
+/// Other errors - like a reset or broken pipe, when the subscriber is really gone - still fail immediately.
+///
+/// A timed out write hasn't written anything (or it would have returned how much), so retrying it can't duplicate output.
+struct PatientWriter<W>(W);
+
+impl<W: Write> Write for PatientWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut timeouts = 0;
+        loop {
+            match self.0.write(buf) {
+                Err(e) if is_timeout(&e) && timeouts < WRITE_RETRIES => timeouts += 1,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                other => return other,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+}
+
+/// Adds the number of bytes written through it to a count, like [`StreamStats::bytes`].
+struct CountingWriter<'c, W>(W, &'c AtomicU64);
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.0.write(buf)?;
+        self.1.fetch_add(written as u64, SeqCst);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+}
+
+/// Writes one response on behalf of a request, so every response shares a status line format and gets counted and logged.
+struct Response<'c> {
+    common:      &'c Common,
+    version:     &'static str,
+    date:        String,
+    started:     Instant,
+    peer:        Peer,
+    cors_origin: Option<&'c str>, // sent with every response, once routed somewhere that allows cross-origin use
+    method:      String, // "-" until the request line is parsed
+    path:        String, // "-" until the request line is parsed
+}
+
+impl<'c> Response<'c> {
+    fn new(common: &'c Common, peer: Peer) -> Self {
+        Self { common, version: "HTTP/1.0", date: http_date(SystemTime::now()), started: Instant::now(), peer, cors_origin: None, method: "-".into(), path: "-".into() }
+    }
+
+    /// Write `status` (e.g. `"404 Not Found"`), `Date`, `Server`, and `headers` (each terminated by CRLF) followed by `body` - unless this is a HEAD request,
+    /// which gets the very same headers (`Content-Length` included) without the body.
+    ///
+    /// `headers` must frame `body`: with `Content-Length` (even if it's 0), or `Connection: close` for streams.  Only 1xx, 204, 304,
+    /// and responses to HEAD go without either, since they never have a body (RFC 7230 § 3.3.3).  Errors get this from [`Self::send_error`].
+    fn send(&self, mut w: impl Write, status: &str, headers: &str, body: &[u8]) -> io::Result<()> {
+        let Self { common, version, date, started, peer, cors_origin, method, path } = self;
+        let cors = match cors_origin {
+            Some(origin)    => format!("Access-Control-Allow-Origin: {origin}\r\n"),
+            None            => String::new(),
+        };
+        let server = match &common.config.server_name {
+            Some(name)      => format!("Server: {name}\r\n"),
+            None            => String::new(),
+        };
+        let head = format!("{version} {status}\r\nDate: {date}\r\n{server}{cors}{headers}\r\n");
+        let body = if method == "HEAD" { b"" } else { body };
+        w.write_all(head.as_bytes())?;
+        w.write_all(body)?;
+        common.metrics.responded(status);
+
+        let code = status.split(' ').next().unwrap_or(status);
+        let bytes = head.len() + body.len();
+        let ms = started.elapsed().as_millis();
+        info!("response: peer={peer} method={method} path={path:?} status={code} bytes={bytes} ms={ms}");
+        Ok(())
+    }
+
+    /// [`Self::send`] an error `status` with a short plain text body explaining it - just the headers, for HEAD requests.
+    fn send_error(&self, w: impl Write, status: &str, headers: &str) -> io::Result<()> {
+        self.send_error_detail(w, status, headers, "")
+    }
+
+    /// Respond to a refused request with `e`'s status and, after that, its description in the body - or return it, if it's an IO error.
+    fn fail(&self, w: impl Write, e: HandlerError) -> io::Result<()> {
+        if let HandlerError::Io(e) = e { return Err(e) }
+        debug!("refused request from {}: {} {e}", self.peer, e.status());
+        self.send_error_detail(w, e.status(), &format!("{}Connection: close\r\n", e.headers()), &e.to_string())
+    }
+
+    /// [`Self::send_error`], with a line of `detail` in the body after the status - unless it's empty.
+    fn send_error_detail(&self, w: impl Write, status: &str, headers: &str, detail: &str) -> io::Result<()> {
+        let body = if detail.is_empty() { format!("{status}\n") } else { format!("{status}\n{detail}\n") };
+        let body_len = body.len();
+        let headers = format!("Content-Type: text/plain; charset=UTF-8\r\nContent-Length: {body_len}\r\n{headers}");
+        self.send(w, status, &headers, body.as_bytes())
+    }
+}
+
+/// The status, range headers, and slice of `body` to respond with, given a request's `Range` header (if any).
+///
+/// Only a single `bytes` range is supported - anything else is ignored, as RFC 7233 permits, and gets the whole `body`.
+fn byte_range<'b>(range: Option<&str>, body: &'b [u8]) -> (&'static str, String, &'b [u8]) {
+    let len = body.len();
+    let whole = ("200 OK", String::from("Accept-Ranges: bytes\r\n"), body);
+    let Some((first, last)) = range.and_then(|r| r.strip_prefix("bytes=")).and_then(|r| r.trim().split_once('-')) else { return whole };
+    let parse = |n: &str| if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) { n.parse::<usize>().ok() } else { None };
+    let (start, end) = match (parse(first), parse(last)) {
+        (Some(first), Some(last)) if first <= last  => (first, last.saturating_add(1).min(len)),
+        (Some(first), None) if last.is_empty()      => (first, len),
+        (None, Some(suffix)) if first.is_empty()    => (len - suffix.min(len), len),
+        _                                           => return whole, // malformed, or several ranges
+    };
+    if start >= end { return ("416 Range Not Satisfiable", format!("Content-Range: bytes */{len}\r\n"), b"") }
+    ("206 Partial Content", format!("Content-Range: bytes {start}-{}/{len}\r\n", end - 1), &body[start..end])
+}
+
+/// Encode standard (RFC 4648 § 4) base64, with padding.
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET : &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().zip([16, 8, 0]).fold(0u32, |n, (&b, shift)| n | (u32::from(b) << shift));
+        for i in 0 .. 4 {
+            encoded.push(if i <= chunk.len() { char::from(ALPHABET[(n >> (18 - 6 * i) & 63) as usize]) } else { '=' });
+        }
+    }
+    encoded
+}
+
+/// Decode standard (RFC 4648 § 4) base64, with or without padding.
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=');
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for b in encoded.bytes() {
+        let sextet = match b {
+            b'A' ..= b'Z'   => b - b'A',
+            b'a' ..= b'z'   => b - b'a' + 26,
+            b'0' ..= b'9'   => b - b'0' + 52,
+            b'+'            => 62,
+            b'/'            => 63,
+            _               => return None,
+        };
+        acc = (acc << 6) | u32::from(sextet);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// Compare secrets without returning early at the first difference, so response times don't leak how much matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// A weak `ETag` for `body` - weak, since the same tag is used whether or not the body is gzipped.
+fn entity_tag(body: &[u8]) -> String {
+    // FNV-1a: https://datatracker.ietf.org/doc/html/draft-eastlake-fnv
+    let hash = body.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &b| (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3));
+    format!("W/\"{hash:016x}\"")
+}
+
+/// Whether an `If-None-Match` value lists `etag` (or is `*`), using the weak comparison RFC 7232 calls for.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| tag.trim().trim_start_matches("W/") == etag)
+}
+
+/// Whether an `Accept-Encoding` value lists `gzip` without ruling it out via `q=0`.
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let q = params.find_map(|param| param.strip_prefix("q=")).map_or(Some(1.0), |q| q.parse::<f32>().ok());
+        (name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip")) && q.is_some_and(|q| q > 0.0)
+    })
+}
+
+/// Whether gzip is likely to shrink a body of `content_type` - already compressed images and such don't.
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/") || matches!(content_type, "application/json" | "application/wasm" | "image/svg+xml")
+}
+
+/// The `Content-Type` to serve `path` with, based on its extension.
+fn content_type_for(path: &str) -> &'static str {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let Some((_, ext)) = file_name.rsplit_once('.') else { return "application/octet-stream" };
+    match &*ext.to_ascii_lowercase() {
+        "html" | "htm"  => "text/html; charset=UTF-8",
+        "css"           => "text/css; charset=UTF-8",
+        "js" | "mjs"    => "text/javascript; charset=UTF-8",
+        "json"          => "application/json",
+        "txt"           => "text/plain; charset=UTF-8",
+        "png"           => "image/png",
+        "jpg" | "jpeg"  => "image/jpeg",
+        "gif"           => "image/gif",
+        "svg"           => "image/svg+xml",
+        "ico"           => "image/x-icon",
+        "wasm"          => "application/wasm",
+        _               => "application/octet-stream",
+    }
+}
+
+/// Maps a decoded request `path` onto a file within `static_dir`, or returns [`None`] if it would escape it.
+fn static_file_path(static_dir: &Path, path: &str) -> Option<PathBuf> {
+    let mut file_path = static_dir.to_path_buf();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        // Besides `..`, refuse anything a platform might treat as a separator, drive, or root.
+        if segment == "." || segment == ".." || segment.contains(['\\', ':', '\0']) { return None }
+        file_path.push(segment);
+    }
+    Some(file_path)
+}
+
+/// `path` with repeated `/`s collapsed, and without a trailing `/` (unless that's all there is) - so `//chat/` is routed like `/chat`.
+///
+/// Dot segments are left alone, rather than resolved: `static_file_path` refuses them.
+fn normalize_path(path: &str) -> Cow<'_, str> {
+    if !path.contains("//") && (path.len() <= 1 || !path.ends_with('/')) { return path.into() }
+    let mut normalized = String::with_capacity(path.len());
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() { normalized.push('/') }
+    normalized.into()
+}
+
+/// Decodes percent escapes in a request path, or returns [`None`] if any are malformed or decode to invalid UTF-8.
+///
+/// `%2F` (an escaped `/`) is deliberately left encoded: it's part of a path segment, never a separator, so it must not
+/// turn e.g. `/chat%2Frust` into the `/chat/rust` route.  Nor can it sneak a `/` into something matched segment by segment.
+fn percent_decode(path: &str) -> Option<Cow<'_, str>> {
+    if !path.contains('%') { return Some(path.into()) }
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match hex_byte(bytes.get(i+1..i+3)?)? {
+            b'/'    => decoded.extend_from_slice(&bytes[i..i+3]),
+            byte    => decoded.push(byte),
+        }
+        i += 3;
+    }
+    String::from_utf8(decoded).ok().map(Cow::Owned)
+}
+
+/// Splits an `application/x-www-form-urlencoded` style query string into decoded key/value pairs, in order.
+///
+/// Keys may repeat, and keys without a `=` get an empty value.  Malformed percent escapes are kept as-is rather than rejected.
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&').filter(|pair| !pair.is_empty()).map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (query_decode(key), query_decode(value))
+    }).collect()
+}
+
+/// The first value for `key` in `query`, if any.
+fn query_value<'q>(query: &'q [(String, String)], key: &str) -> Option<&'q str> {
+    query.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+}
+
+fn query_decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i+1..i+3).and_then(hex_byte)) {
+            (b'+', _)           => { decoded.push(b' ');  i += 1; },
+            (b'%', Some(byte))  => { decoded.push(byte);  i += 3; },
+            (b, _)              => { decoded.push(b);     i += 1; },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parses two hex digits, e.g. the `2F` of a `%2F` escape.
+fn hex_byte(hex: &[u8]) -> Option<u8> {
+    let digit = |b: u8| (b as char).to_digit(16);
+    match *hex {
+        [hi, lo] => Some((digit(hi)? * 16 + digit(lo)?) as u8),
+        _ => None,
+    }
+}
+
+/// Escapes `text` for safe inclusion in HTML text or quoted attribute values.
+fn html_escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) { return text.into() }
+    let mut escaped = String::with_capacity(text.len() + 16);
+    for ch in text.chars() {
+        match ch {
+            '&'     => escaped.push_str("&amp;"),
+            '<'     => escaped.push_str("&lt;"),
+            '>'     => escaped.push_str("&gt;"),
+            '"'     => escaped.push_str("&quot;"),
+            '\''    => escaped.push_str("&#39;"),
+            ch      => escaped.push(ch),
+        }
+    }
+    escaped.into()
+}
+
+/// Undo [`html_escape`].
+fn html_unescape(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&#39;", "'").replace("&amp;", "&")
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body from the start of `encoded`, discarding any chunk extensions and trailers.
+///
+/// Returns `Ok(None)` if `encoded` doesn't contain the entire body yet, otherwise the decoded body and how many bytes of `encoded` it spanned.
+fn decode_chunked(encoded: &[u8]) -> Result<Option<(Vec<u8>, usize)>, ()> {
+    // https://datatracker.ietf.org/doc/html/rfc7230#section-4.1
+    let mut decoded = Vec::new();
+    let mut pos = 0;
+    loop {
+        let Some(eol) = encoded[pos..].windows(2).position(|w| w == b"\r\n") else { return Ok(None) };
+        let size = encoded[pos..pos+eol].split(|&b| b == b';').next().unwrap_or_default();
+        let size = std::str::from_utf8(size).map_err(drop)?.trim_end_matches([' ', '\t']);
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) { return Err(()) }
+        let size = usize::from_str_radix(size, 16).map_err(drop)?;
+        pos += eol + 2;
+
+        if size == 0 {
+            loop { // trailer fields, until an empty line
+                let Some(eol) = encoded[pos..].windows(2).position(|w| w == b"\r\n") else { return Ok(None) };
+                pos += eol + 2;
+                if eol == 0 { return Ok(Some((decoded, pos))) }
+            }
+        }
+
+        if encoded.len() - pos < size.saturating_add(2) { return Ok(None) }
+        decoded.extend_from_slice(&encoded[pos..pos+size]);
+        pos += size;
+        if &encoded[pos..pos+2] != b"\r\n" { return Err(()) }
+        pos += 2;
+    }
+}
+
+/// Formats `time` as an RFC 7231 IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    let weekday = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"][(days % 7) as usize]; // 1970-01-01 was a Thursday
+    let (year, month, day) = civil_from_days(days as i64);
+    let month = MONTHS[month as usize - 1];
+    format!("{weekday}, {day:02} {month} {year} {:02}:{:02}:{:02} GMT", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Parses an IMF-fixdate, as [`http_date`] formats them, into seconds since the Unix epoch.
+///
+/// The obsolete RFC 850 and asctime formats are [`None`] too, so conditions using them are just ignored.
+fn parse_http_date(date: &str) -> Option<u64> {
+    let number = |n: &str, len| n.parse::<u32>().ok().filter(|_| n.len() == len && n.bytes().all(|b| b.is_ascii_digit()));
+    let (_weekday, date) = date.trim().split_once(", ")?;
+    let [day, month, year, time, "GMT"] = date.split(' ').collect::<Vec<_>>()[..] else { return None };
+    let [hour, minute, second] = time.split(':').collect::<Vec<_>>()[..] else { return None };
+    let (day, year, hour, minute, second) = (number(day, 2)?, number(year, 4)?, number(hour, 2)?, number(minute, 2)?, number(second, 2)?);
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    if !(1 ..= 31).contains(&day) || hour > 23 || minute > 59 || second > 60 { return None } // 60 for leap seconds
+    let days = u64::try_from(days_from_civil(i64::from(year), month, day)).ok()?;
+    Some(days * 86400 + u64::from(hour * 3600 + minute * 60 + second))
+}
+
+const MONTHS : [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Days since 1970-01-01 to a (year, month 1..=12, day 1..=31) proleptic Gregorian date.
+///
+/// See Howard Hinnant's <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z   = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);                                 // [0, 146096]
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;      // [0, 399]
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);                    // [0, 365]
+    let mp  = (5*doy + 2) / 153;                                    // [0, 11]
+    let d   = doy - (153*mp + 2)/5 + 1;                             // [1, 31]
+    let m   = if mp < 10 { mp + 3 } else { mp - 9 };                // [1, 12]
+    (yoe + era*400 + (m <= 2) as i64, m as u32, d as u32)
+}
+
+/// The inverse of [`civil_from_days`]: days since 1970-01-01 of a proleptic Gregorian (year, month 1..=12, day 1..=31).
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y   = year - (month <= 2) as i64;
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);                                    // [0, 399]
+    let mp  = i64::from((month + 9) % 12);                          // [0, 11], from March
+    let doy = (153*mp + 2)/5 + i64::from(day) - 1;                  // [0, 365]
+    let doe = yoe*365 + yoe/4 - yoe/100 + doy;                      // [0, 146096]
+    era*146097 + doe - 719468
+}
+
+/// How [`stream_chat`] writes a room's messages, which are stored as SSE events.
+#[derive(Clone, Copy)]
+enum Framing {
+    Sse,
+    /// One JSON object per line - see [`ndjson`].
+    NdJson,
+}
+
+impl Framing {
+    fn content_type(self) -> &'static str {
+        match self {
+            Framing::Sse    => "text/event-stream; charset=UTF-8",
+            Framing::NdJson => "application/x-ndjson",
+        }
+    }
+
+    fn message(self, mut w: impl Write, id: Option<u64>, msg: &str) -> io::Result<()> {
+        match self {
+            Framing::Sse    => match id {
+                Some(id)    => write!(w, "id: {id}\n{msg}"),
+                None        => write!(w, "{msg}"), // live, so nothing to resume from - EventSource keeps the last id it had
+            },
+            Framing::NdJson => writeln!(w, "{}", ndjson(id, msg)),
+        }
+    }
+
+    /// Messages follow immediately.
+    fn lagged(self, mut w: impl Write, missed: u64) -> io::Result<()> {
+        match self {
+            Framing::Sse    => write!(w, "event: gap\ndata: {missed}\n\n"),
+            Framing::NdJson => writeln!(w, "{{\"event\":\"gap\",\"missed\":{missed}}}"),
+        }
+    }
+
+    /// A `--motd`, escaped like any other message.
+    fn system(self, mut w: impl Write, text: &str) -> io::Result<()> {
+        match self {
+            Framing::Sse    => {
+                let data = text.replace("\r\n", "\n").replace('\r', "\n");
+                let data = data.lines().map(|line| format!("data: {}\n", html_escape(line))).collect::<Vec<_>>().join("");
+                write!(w, "event: system\n{data}\n")
+            },
+            Framing::NdJson => writeln!(w, "{{\"event\":\"system\",\"text\":{}}}", json::quote(text)),
+        }
+    }
+
+    fn shutdown(self, mut w: impl Write) -> io::Result<()> {
+        match self {
+            Framing::Sse    => write!(w, "event: shutdown\ndata: bye\n\n"),
+            Framing::NdJson => writeln!(w, "{{\"event\":\"shutdown\"}}"),
+        }
+    }
+
+    fn keepalive(self, mut w: impl Write) -> io::Result<()> {
+        match self {
+            Framing::Sse    => write!(w, ": keepalive\n\n"), // a comment, which EventSource ignores
+            Framing::NdJson => writeln!(w, "{{\"event\":\"heartbeat\"}}"),
+        }
+    }
+}
+
+/// Stream `room`'s messages after `cursor` to a subscriber - after `--motd`, if any, for `new` subscribers (rather than resuming ones).
+#[allow(clippy::too_many_arguments)] // each subscriber's whole state, moved onto its own thread
+fn stream_chat(common: &Common, room: &Broadcast, stream: &TcpStream, peer: Peer, user: &str, mut cursor: Cursor, framing: Framing, new: bool) -> io::Result<()> {
+    let summary = StreamSummary::new(common, peer, &room.name);
+    let _subscriber = SubscriberGuard::new(common);
+    stream.set_read_timeout(None)?; // quiet clients are expected - they've nothing more to send
+    let mut w = BufWriter::new(CountingWriter(PatientWriter(stream), &summary.stats.bytes));
+    let deliver = |w: &mut BufWriter<_>, messages: &[(Option<u64>, Arc<String>)]| {
+        let written = messages.iter().try_for_each(|(id, msg)| framing.message(&mut *w, *id, msg)).and_then(|()| w.flush());
+        if written.is_ok() { summary.stats.messages.fetch_add(messages.len(), SeqCst); }
+        written
+    };
+
+    // The scrollback (which a single receive gets all of) and then `--motd`, before anything live - this subscriber's own join included.
+    if let Some(motd) = common.config.motd.as_deref().filter(|_| new) {
+        let replayed = loop {
+            match room.recv_timeout(&mut cursor, Duration::ZERO, &AtomicBool::new(false)) {
+                Ok(Recv::Lagged(missed))        => if let Err(e) = framing.lagged(&mut w, missed) { break Err(e) },
+                Ok(Recv::Messages(messages))    => break deliver(&mut w, &messages),
+                Err(_)                          => break Ok(()), // no scrollback - or shutting down, which is noticed below
+            }
+        };
+        if let Err(e) = replayed.and_then(|()| framing.system(&mut w, motd)).and_then(|()| w.flush()) { summary.reason.set("write error"); return Err(e) }
+    }
+    let _presence = PresenceGuard::new(room, user);
+
+    // Without reading, a hang up would only be noticed when the next write fails - which for a quiet room is the next ping.
+    let hung_up = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let _ = io::copy(&mut { stream }, &mut io::sink()); // until EOF, or the error of a reset or our own shutdown
+            hung_up.store(true, SeqCst);
+            room.wake();
+        });
+
+        let result = loop {
+            let written = match room.recv_timeout(&mut cursor, common.config.ping_interval, &hung_up) {
+                Err(RecvTimeoutError::Timeout) if hung_up.load(SeqCst) => {
+                    debug!("subscriber {peer} hung up on room {:?}", room.name);
+                    summary.reason.set("client closed");
+                    break Ok(());
+                },
+                Ok(Recv::Messages(messages)) => deliver(&mut w, &messages),
+                Ok(Recv::Lagged(missed)) => framing.lagged(&mut w, missed),
+                Err(RecvTimeoutError::Disconnected) => {
+                    summary.reason.set("disconnected"); // shutting down, or kicked
+                    break framing.shutdown(&mut w).and_then(|()| w.flush());
+                },
+                Err(RecvTimeoutError::Timeout) => framing.keepalive(&mut w).and_then(|()| w.flush()),
+            };
+            if let Err(e) = written { summary.reason.set("write error"); break Err(e) }
+        };
+
+        // Wake the reader if it's still blocked on the client.
+        let _ = stream.shutdown(Shutdown::Both);
+        result
+    })
+}
+
+fn stream_websocket(common: &Common, room: &Broadcast, stream: &TcpStream, peer: Peer, user: &str, mut cursor: Cursor) -> io::Result<()> {
+    let _subscriber = SubscriberGuard::new(common);
+    let _presence = PresenceGuard::new(room, user);
+    stream.set_read_timeout(None)?; // quiet clients are fine - dead ones are found when our pings fail
+
+    // Both directions at once: a reader thread for client frames, while this thread forwards the room's messages.
+    let writing = Mutex::new(());
+    let send = |opcode, payload: &[u8]| { let _writing = writing.lock().unwrap(); websocket::write_frame(PatientWriter(stream), opcode, payload) };
+    let close = |code: u16| send(websocket::CLOSE, &code.to_be_bytes());
+    let closed = AtomicBool::new(false);
+
+    std::thread::scope(|scope| {
+        let reader = scope.spawn(|| {
+            let result = read_websocket(common, room, stream, peer, user, &send, &close);
+            closed.store(true, SeqCst);
+            room.wake();
+            let _ = stream.shutdown(Shutdown::Both); // the closing handshake is done, or it's too late for one
+            result
+        });
+
+        let result = loop {
+            if closed.load(SeqCst) { break Ok(()) }
+            match room.recv_timeout(&mut cursor, common.config.ping_interval, &closed) {
+                Err(RecvTimeoutError::Timeout) if closed.load(SeqCst) => break Ok(()),
+                Ok(Recv::Messages(messages)) => {
+                    // The JSON objects of `/chat/stream.ndjson`, rather than the SSE events' bare data - plain messages' text is
+                    // HTML-escaped, and the others' JSON, with nothing to tell them apart but the `event` each object carries.
+                    let sent = messages.iter().try_for_each(|(id, msg)| send(websocket::TEXT, ndjson(*id, msg).as_bytes()));
+                    if let Err(e) = sent { break Err(e) }
+                },
+                Ok(Recv::Lagged(missed)) => if let Err(e) = send(websocket::TEXT, format!("{{\"event\":\"gap\",\"missed\":{missed}}}").as_bytes()) { break Err(e) },
+                Err(RecvTimeoutError::Disconnected) => break close(1001), // going away
+                Err(RecvTimeoutError::Timeout) => if let Err(e) = send(websocket::PING, b"") { break Err(e) },
+            }
+        };
+
+        // Wake the reader if it's still blocked on the client.
+        let _ = stream.shutdown(Shutdown::Both);
+        let read_result = reader.join().unwrap();
+        if closed.load(SeqCst) && result.is_err() { return read_result } // writes racing the closing handshake are expected to fail
+        result.and(read_result)
+    })
+}
+
+fn read_websocket(common: &Common, room: &Broadcast, stream: &TcpStream, peer: Peer, user: &str, send: &dyn Fn(u8, &[u8]) -> io::Result<()>, close: &dyn Fn(u16) -> io::Result<()>) -> io::Result<()> {
+    let mut message = Vec::new();
+    let mut fragmented = false; // expecting a continuation of `message`
+    loop {
+        let frame = match websocket::read_frame(stream, common.config.max_message) {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()), // hung up, or we shut down the socket
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => return close(1002), // protocol error
+            Err(e) => return Err(e),
+        };
+        match frame.opcode {
+            websocket::PING                         => { send(websocket::PONG, &frame.payload)?; continue },
+            websocket::PONG                         => continue,
+            websocket::CLOSE                        => return send(websocket::CLOSE, frame.payload.get(..2).unwrap_or_default()), // echo the status code
+            websocket::TEXT if !fragmented          => message = frame.payload,
+            websocket::CONTINUATION if fragmented   => message.extend(frame.payload),
+            websocket::BINARY if !fragmented        => return close(1003), // unsupported data
+            _                                       => return close(1002), // protocol error
+        }
+        fragmented = !frame.fin;
+        if message.len() > common.config.max_message { return close(1009) } // message too big
+        if fragmented { continue }
+
+        let Ok(text) = std::str::from_utf8(&message) else { return close(1007) }; // invalid payload data
+        let limit = match peer.0 {
+            Some(peer)  => common.rate_limiter.lock().unwrap().take(peer.ip(), common.config.rate_limit, common.config.rate_burst),
+            None        => Ok(()),
+        };
+        let Some(text) = common.filter(text) else { debug!("dropped websocket message from {peer}: banned word"); continue };
+        match limit {
+            Ok(()) if common.is_repeat(peer, room, &text) => debug!("dropped websocket message from {peer}: repeated"),
+            Ok(())  => common.post(room, user, &text),
+            Err(_)  => debug!("dropped websocket message from {peer}: rate limited"),
+        }
+    }
+}
+
+/// The room (by default `general`) and user named by a `--allow-raw` greeting line, `CHAT [room [user]]` - or [`None`] if `line` isn't one.
+fn raw_greeting(line: &[u8]) -> Option<(&str, Option<String>)> {
+    let line = std::str::from_utf8(line.strip_suffix(b"\r").unwrap_or(line)).ok()?;
+    let rest = line.strip_prefix("CHAT")?;
+    if !rest.is_empty() && !rest.starts_with(' ') { return None }
+    let (room, user) = rest.trim_start().split_once(' ').unwrap_or((rest.trim_start(), ""));
+    Some((if room.is_empty() { "general" } else { room }, sanitize_user_name(user)))
+}
+
+/// Start a `--allow-raw` session in `room` on its own thread, with `pending` bytes already read after the greeting.
+fn raw_session(common: &Arc<Common>, stream: &TcpStream, peer: Peer, room: &str, user: Option<String>, pending: Vec<u8>) -> io::Result<()> {
+    let mut w = stream;
+    if !is_valid_room_name(room) { return w.write_all(b"* invalid room name\r\n") }
+    let Some(room) = common.room(room) else { return w.write_all(b"* unavailable\r\n") };
+    let Some(cursor) = room.subscribe_recent(common.config.history) else { return w.write_all(b"* unavailable\r\n") };
+    info!("raw session: peer={peer} room={:?}", room.name);
+
+    let stream = stream.try_clone()?;
+    let connection = ConnectionGuard::new(common);
+    let user = user.unwrap_or_else(|| String::from("anonymous"));
+    std::thread::spawn(move || log_connection_error(peer, stream_raw(&connection.0, &room, &stream, peer, &user, cursor, pending)));
+    Ok(())
+}
+
+fn stream_raw(common: &Common, room: &Broadcast, stream: &TcpStream, peer: Peer, user: &str, mut cursor: Cursor, pending: Vec<u8>) -> io::Result<()> {
+    let _subscriber = SubscriberGuard::new(common);
+    let _presence = PresenceGuard::new(room, user);
+    stream.set_read_timeout(None)?; // lurkers are fine
+
+    let hung_up = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let reader = scope.spawn(|| {
+            let result = read_raw(common, room, io::BufReader::new(io::Cursor::new(pending).chain(stream)), peer, user);
+            hung_up.store(true, SeqCst);
+            room.wake();
+            result
+        });
+
+        let mut w = BufWriter::new(PatientWriter(stream));
+        let result = loop {
+            let written = match room.recv_timeout(&mut cursor, common.config.ping_interval, &hung_up) {
+                Err(RecvTimeoutError::Timeout) if hung_up.load(SeqCst) => break Ok(()),
+                Ok(Recv::Messages(messages)) => {
+                    let mut lines = messages.iter().filter_map(|(_id, msg)| plain_text(msg)).flat_map(|text| text.lines().map(str::to_string).collect::<Vec<_>>());
+                    lines.try_for_each(|line| write!(w, "{line}\r\n")).and_then(|()| w.flush())
+                },
+                Ok(Recv::Lagged(missed)) => write!(w, "* missed {missed} messages\r\n"), // messages follow immediately
+                Err(RecvTimeoutError::Disconnected) => break write!(w, "* disconnected\r\n").and_then(|()| w.flush()),
+                Err(RecvTimeoutError::Timeout) => Ok(()), // hang ups are noticed by the reader instead
+            };
+            if let Err(e) = written { break Err(e) }
+        };
+
+        // Wake the reader if it's still blocked on the client.
+        let _ = stream.shutdown(Shutdown::Both);
+        result.and(reader.join().unwrap())
+    })
+}
+
+fn read_raw(common: &Common, room: &Broadcast, mut r: impl io::BufRead, peer: Peer, user: &str) -> io::Result<()> {
+    let max_line = common.config.max_message as u64 + 2; // CRLF
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if io::BufRead::read_until(&mut (&mut r).take(max_line), b'\n', &mut line)? == 0 { return Ok(()) } // hung up, or we shut down the socket
+        if !line.ends_with(b"\n") && line.len() as u64 == max_line { debug!("dropped raw session from {peer}: line too long"); return Ok(()) }
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end_matches(['\r', '\n']);
+        if text.is_empty() { continue }
+        let limit = match peer.0 {
+            Some(peer)  => common.rate_limiter.lock().unwrap().take(peer.ip(), common.config.rate_limit, common.config.rate_burst),
+            None        => Ok(()),
+        };
+        let Some(text) = common.filter(text) else { debug!("dropped raw message from {peer}: banned word"); continue };
+        match limit {
+            Ok(()) if common.is_repeat(peer, room, &text) => debug!("dropped raw message from {peer}: repeated"),
+            Ok(())  => common.post(room, user, &text),
+            Err(_)  => debug!("dropped raw message from {peer}: rate limited"),
+        }
+    }
+}
+
+/// A plain `message` event's time and text, given its `data` - see [`Common::post`].
+fn message_time(data: &str) -> (Option<u64>, &str) {
+    if let Some((time, text)) = data.split_once('\n') {
+        if let Ok(time) = time.parse() { return (Some(time), text) }
+    }
+    (None, data)
+}
+
+/// The type and data of an SSE event, as stored for a room's message - its `data` lines rejoined.
+fn sse_fields(event: &str) -> (&str, String) {
+    let kind = event.lines().find_map(|line| line.strip_prefix("event: ")).unwrap_or("message");
+    let data = event.lines().filter_map(|line| line.strip_prefix("data: ")).collect::<Vec<_>>().join("\n");
+    (kind, data)
+}
+
+/// A room's message - stored as an SSE event - as a line of JSON for `/chat/stream.ndjson`, `/chat/messages`, and `/ws`:
+/// `{"id":1,"event":"message","time":...,"text":"..."}` for plain messages, and the event's own JSON as `data` for the rest.
+/// Live messages (e.g. `presence`) have no `id`.
+fn ndjson(id: Option<u64>, event: &str) -> String {
+    let (kind, data) = sse_fields(event);
+    let id = id.map_or(String::new(), |id| format!("\"id\":{id},"));
+    match kind {
+        "message" => {
+            let (time, text) = message_time(&data);
+            let time = time.map_or(String::new(), |time| format!(",\"time\":{time}"));
+            format!("{{{id}\"event\":\"message\"{time},\"text\":{}}}", json::quote(&html_unescape(text)))
+        },
+        kind => {
+            let data = if json::parse(&data).is_some() { data } else { json::quote(&data) };
+            format!("{{{id}\"event\":{},\"data\":{data}}}", json::quote(kind))
+        },
+    }
+}
+
+/// How a room's message - stored as an SSE event - reads in a `--allow-raw` session, if at all.
+fn plain_text(event: &str) -> Option<String> {
+    let (kind, data) = sse_fields(event);
+    let text = match kind {
+        "message"   => html_unescape(message_time(&data).1),
+        "chat"      => { let json = json::parse(&data)?; format!("{}: {}", json.get("user")?.as_str()?, json.get("text")?.as_str()?) },
+        "presence"  => {
+            let json = json::parse(&data)?;
+            let action = if json.get("action")?.as_str()? == "join" { "joined" } else { "left" };
+            format!("* {} {action}", json.get("user")?.as_str()?)
+        },
+        "cleared"   => String::from("* scrollback cleared"),
+        _           => return None,
+    };
+    // Whatever was posted, it doesn't get to send escape sequences to the client's terminal.
+    Some(text.chars().map(|c| if c.is_control() && c != '\n' { '\u{FFFD}' } else { c }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_chunked_reassembles_chunks_and_skips_extensions_and_trailers() {
+        let encoded = b"6\r\nhello \r\n5;ext=1\r\nworld\r\n0\r\nTrailer: x\r\n\r\nGET / HTTP/1.1";
+        assert_eq!(decode_chunked(encoded), Ok(Some((b"hello world".to_vec(), encoded.len() - b"GET / HTTP/1.1".len()))));
+        assert_eq!(decode_chunked(b"6\r\nhello "), Ok(None));
+        assert_eq!(decode_chunked(b"0\r\n"), Ok(None));
+        assert_eq!(decode_chunked(b"zz\r\nhello\r\n0\r\n\r\n"), Err(()));
+        assert_eq!(decode_chunked(b"5\r\nhello!!0\r\n\r\n"), Err(()));
+    }
+
+    #[test]
+    fn broadcast_delivers_every_message_once_to_every_subscriber() {
+        const SUBSCRIBERS : usize = 50;
+        const MESSAGES : u64 = 1000;
+        let room = Broadcast::new("test", MESSAGES as usize);
+        let ready = std::sync::Barrier::new(SUBSCRIBERS + 1);
+        std::thread::scope(|scope| {
+            let subscribers = (0 .. SUBSCRIBERS).map(|_| scope.spawn(|| {
+                let mut cursor = room.subscribe(None).unwrap();
+                ready.wait();
+                let mut received = Vec::new();
+                while received.len() < MESSAGES as usize {
+                    match room.recv_timeout(&mut cursor, Duration::from_secs(10), &AtomicBool::new(false)) {
+                        Ok(Recv::Messages(messages))    => received.extend(messages.into_iter().map(|(id, msg)| (id, msg.parse::<u64>().unwrap()))),
+                        Ok(Recv::Lagged(missed))        => panic!("lagged by {missed} messages"),
+                        Err(e)                          => panic!("{e:?} after {} messages", received.len()),
+                    }
+                }
+                received
+            })).collect::<Vec<_>>();
+
+            ready.wait();
+            for n in 1 ..= MESSAGES { room.send(Arc::new(n.to_string())) }
+            for subscriber in subscribers {
+                assert_eq!(subscriber.join().unwrap(), (1 ..= MESSAGES).map(|n| (Some(n), n)).collect::<Vec<_>>());
+            }
+        });
+    }
+
+    #[test]
+    fn broadcast_reports_how_many_messages_a_lagging_subscriber_missed() {
+        let room = Broadcast::new("test", 4);
+        let mut cursor = room.subscribe(None).unwrap();
+        for n in 1 ..= 10 { room.send(Arc::new(n.to_string())) }
+        let never = AtomicBool::new(false);
+        assert!(matches!(room.recv_timeout(&mut cursor, Duration::ZERO, &never), Ok(Recv::Lagged(6))));
+        let Ok(Recv::Messages(messages)) = room.recv_timeout(&mut cursor, Duration::ZERO, &never) else { panic!("expected the retained messages") };
+        assert_eq!(messages.iter().map(|(id, msg)| (*id, msg.as_str())).collect::<Vec<_>>(), [(Some(7), "7"), (Some(8), "8"), (Some(9), "9"), (Some(10), "10")]);
+        assert!(matches!(room.recv_timeout(&mut cursor, Duration::ZERO, &never), Err(RecvTimeoutError::Timeout)));
+    }
+
+    #[test]
+    fn broadcast_wakes_subscribers_with_disconnected_once_closed() {
+        let room = Broadcast::new("test", 4);
+        let mut cursor = room.subscribe(None).unwrap();
+        room.send(Arc::new(String::from("last")));
+        room.close();
+        assert!(room.subscribe(None).is_none());
+        assert!(matches!(room.recv_timeout(&mut cursor, Duration::ZERO, &AtomicBool::new(false)), Ok(Recv::Messages(_))));
+        assert!(matches!(room.recv_timeout(&mut cursor, Duration::from_secs(10), &AtomicBool::new(false)), Err(RecvTimeoutError::Disconnected)));
+    }
+
+    /// The messages `room` retains, as sent.
+    fn retained(room: &Broadcast) -> Vec<String> {
+        room.state.lock().unwrap().messages.iter().map(|msg| msg.to_string()).collect()
+    }
+
+    #[test]
+    fn posted_messages_are_html_escaped() {
+        let common = Common::default();
+        let room = common.room("general").unwrap();
+        common.post(&room, "<b>mallory</b>", "<script>alert('&\"')</script>");
+        let [event] = &retained(&room)[..] else { panic!("expected one message") };
+        assert!(event.contains("\ndata: &lt;b&gt;mallory&lt;/b&gt;: &lt;script&gt;alert(&#39;&amp;&quot;&#39;)&lt;/script&gt;\n"), "{event:?}");
+        assert!(!event.contains('<'));
+    }
+
+    #[test]
+    fn html_escape_only_allocates_when_needed() {
+        assert!(matches!(html_escape("plain text"), Cow::Borrowed("plain text")));
+        assert_eq!(html_escape("a < b && c > \"d\" 'e'"), "a &lt; b &amp;&amp; c &gt; &quot;d&quot; &#39;e&#39;");
+        assert_eq!(html_unescape(&html_escape("<&amp;>")), "<&amp;>");
+    }
+
+    #[test]
+    fn percent_decode_decodes_everything_but_slashes() {
+        assert_eq!(percent_decode("/%63hat").as_deref(), Some("/chat"));
+        assert_eq!(percent_decode("/caf%C3%A9").as_deref(), Some("/café"));
+        assert_eq!(percent_decode("/chat%2Frust").as_deref(), Some("/chat%2Frust")); // a segment's own `/`, never a separator
+        assert_eq!(percent_decode("/chat%2frust").as_deref(), Some("/chat%2frust"));
+        assert!(matches!(percent_decode("/plain"), Some(Cow::Borrowed("/plain"))));
+        assert_eq!(percent_decode("/%zz"), None);
+        assert_eq!(percent_decode("/%4"), None);
+        assert_eq!(percent_decode("/%FF"), None); // not UTF-8
+    }
+
+    #[test]
+    fn header_names_are_case_insensitive_and_whitespace_is_optional() {
+        let Ok(parsed) = parse_request(b"POST /chat HTTP/1.1\r\nhOsT:localhost\r\ncontent-length:5\r\nCONTENT-TYPE: \t application/JSON ; charset=utf-8\t\r\n\r\nhello", 100) else { panic!("expected a request") };
+        assert_eq!(parsed.headers[1], (String::from("content-length"), String::from("5")));
+        let Ok(headers) = parse_headers(&parsed.headers) else { panic!("expected valid headers") };
+        assert_eq!(headers.host, Some("localhost"));
+        assert_eq!(headers.content_length, Some(5));
+        assert!(headers.json_body);
+    }
+
+    #[test]
+    fn content_type_for_maps_extensions_case_insensitively() {
+        assert_eq!(content_type_for("/index.html"), "text/html; charset=UTF-8");
+        assert_eq!(content_type_for("/css/site.CSS"), "text/css; charset=UTF-8");
+        assert_eq!(content_type_for("/app.js"), "text/javascript; charset=UTF-8");
+        assert_eq!(content_type_for("/logo.png"), "image/png");
+        assert_eq!(content_type_for("/photo.jpeg"), "image/jpeg");
+        assert_eq!(content_type_for("/icon.svg"), "image/svg+xml");
+        assert_eq!(content_type_for("/app.wasm"), "application/wasm");
+        assert_eq!(content_type_for("/archive.tar.gz"), "application/octet-stream");
+        assert_eq!(content_type_for("/README"), "application/octet-stream");
+        assert_eq!(content_type_for("/.d/README"), "application/octet-stream"); // the extension is the file name's, not a directory's
+    }
+
+    #[test]
+    fn byte_range_slices_single_ranges() {
+        let body = b"0123456789";
+        assert_eq!(byte_range(Some("bytes=2-4"), body), ("206 Partial Content", String::from("Content-Range: bytes 2-4/10\r\n"), &b"234"[..]));
+        assert_eq!(byte_range(Some("bytes=8-99"), body).2, b"89");
+        assert_eq!(byte_range(Some("bytes=7-"), body).2, b"789");
+        assert_eq!(byte_range(Some("bytes=-3"), body).2, b"789");
+        assert_eq!(byte_range(Some("bytes=10-"), body).0, "416 Range Not Satisfiable");
+        assert_eq!(byte_range(Some("bytes=4-2"), body).0, "200 OK");
+        assert_eq!(byte_range(Some("bytes=+1-2"), body).0, "200 OK");
+        assert_eq!(byte_range(Some("items=1-2"), body).0, "200 OK");
+        assert_eq!(byte_range(None, body), ("200 OK", String::from("Accept-Ranges: bytes\r\n"), &body[..]));
+    }
+
+    #[test]
+    fn etag_matches_compares_weakly() {
+        let etag = entity_tag(b"body");
+        assert_eq!(etag, entity_tag(b"body"));
+        assert_ne!(etag, entity_tag(b"Body"));
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(etag.trim_start_matches("W/"), &etag));
+        assert!(etag_matches(&format!("\"other\", {etag}"), &etag));
+        assert!(etag_matches(" * ", &etag));
+        assert!(!etag_matches("\"other\"", &etag));
+    }
+
+    #[test]
+    fn base64_round_trips_with_or_without_padding() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar", &[0xFF, 0x00, 0xFE]] {
+            let encoded = base64_encode(bytes);
+            assert_eq!(base64_decode(&encoded).as_deref(), Some(bytes), "{encoded}");
+            assert_eq!(base64_decode(encoded.trim_end_matches('=')).as_deref(), Some(bytes), "{encoded}");
+        }
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64_decode("Zm9v YmFy"), None);
+        assert_eq!(base64_decode("Zm9v-_"), None); // the URL-safe alphabet isn't what Basic authentication uses
+    }
+
+    #[test]
+    fn constant_time_eq_needs_equal_lengths_and_bytes() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn live_messages_reach_current_subscribers_in_order_without_ids_or_scrollback() {
+        let room = Broadcast::new("test", 4);
+        let mut cursor = room.subscribe(None).unwrap();
+        room.send_live(Arc::new(String::from("join")));
+        room.send(Arc::new(String::from("1")));
+        room.send(Arc::new(String::from("2")));
+        room.send_live(Arc::new(String::from("leave")));
+        let never = AtomicBool::new(false);
+        let Ok(Recv::Messages(messages)) = room.recv_timeout(&mut cursor, Duration::ZERO, &never) else { panic!("expected messages") };
+        assert_eq!(messages.iter().map(|(id, msg)| (*id, msg.as_str())).collect::<Vec<_>>(), [(None, "join"), (Some(1), "1"), (Some(2), "2"), (None, "leave")]);
+
+        // Later subscribers get the scrollback, which has no room for these, and the ids carry on.
+        assert_eq!(retained(&room), ["1", "2"]);
+        let mut later = room.subscribe_recent(usize::MAX).unwrap();
+        let Ok(Recv::Messages(messages)) = room.recv_timeout(&mut later, Duration::ZERO, &never) else { panic!("expected the scrollback") };
+        assert_eq!(messages.iter().map(|(id, msg)| (*id, msg.as_str())).collect::<Vec<_>>(), [(Some(1), "1"), (Some(2), "2")]);
+        room.send(Arc::new(String::from("3")));
+        let Ok(Recv::Messages(messages)) = room.recv_timeout(&mut later, Duration::ZERO, &never) else { panic!("expected a message") };
+        assert_eq!(messages[0].0, Some(3));
+
+        // Falling behind on live messages just skips the oldest.
+        for n in 0 .. LIVE_BACKLOG + 10 { room.send_live(Arc::new(n.to_string())) }
+        let Ok(Recv::Messages(messages)) = room.recv_timeout(&mut cursor, Duration::ZERO, &never) else { panic!("expected messages") };
+        assert_eq!(messages.len(), 1 + LIVE_BACKLOG);
+        assert_eq!((messages[0].0, messages[1].1.as_str()), (Some(3), "10"));
+    }
+
+    #[test]
+    fn message_time_reads_the_first_data_line() {
+        let event = "event: message\ndata: 1700000000000\ndata: alice: 42\n\n";
+        assert_eq!(ndjson(Some(1), event), "{\"id\":1,\"event\":\"message\",\"time\":1700000000000,\"text\":\"alice: 42\"}");
+        assert_eq!(plain_text(event).as_deref(), Some("alice: 42"));
+        assert_eq!(message_time("1700000000000\nalice: hi\nthere"), (Some(1700000000000), "alice: hi\nthere"));
+        assert_eq!(message_time("alice: 42\n1700000000000"), (None, "alice: 42\n1700000000000"));
+    }
+
+    #[test]
+    fn parse_request_splits_a_valid_head() {
+        let request = b"GET /chat?room=a%20b HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\nAccept: text/html\r\n\r\nbody";
+        let Ok(parsed) = parse_request(request, 100) else { panic!("expected a request") };
+        assert_eq!((&*parsed.method, &*parsed.target, &*parsed.version), ("GET", "/chat?room=a%20b", "HTTP/1.1"));
+        assert_eq!(parsed.headers, [("Host", "localhost"), ("Accept", "*/*"), ("Accept", "text/html")].map(|(n, v)| (n.to_string(), v.to_string())));
+        assert_eq!(parsed.body_offset, request.len() - b"body".len());
+
+        let Ok(parsed) = parse_request(b"GET /\r\n\r\n", 100) else { panic!("expected a request") }; // HTTP/0.9 style, for `handle_request` to refuse
+        assert_eq!((&*parsed.method, &*parsed.target, &*parsed.version, parsed.headers.len()), ("GET", "/", "", 0));
+    }
+
+    #[test]
+    fn parse_request_waits_for_the_end_of_the_head() {
+        for partial in [&b""[..], b"GET / HTTP/1.1", b"GET / HTTP/1.1\r\nHost: localhost\r\n", b"GET / HTTP/1.1\r\n\r"] {
+            assert!(matches!(parse_request(partial, 100), Err(ParseError::Incomplete)), "{:?}", String::from_utf8_lossy(partial));
+        }
+    }
+
+    #[test]
+    fn parse_request_refuses_malformed_heads() {
+        for bad in [&b"GET\r\n\r\n"[..], b" / HTTP/1.1\r\n\r\n", b"G(T / HTTP/1.1\r\n\r\n", b"GET /\x07 HTTP/1.1\r\n\r\n"] {
+            assert!(matches!(parse_request(bad, 100), Err(ParseError::BadRequestLine)), "{:?}", String::from_utf8_lossy(bad));
+        }
+        for bad in [&b"GET / HTTP/1.1\r\nHost\r\n\r\n"[..], b"GET / HTTP/1.1\r\nHost : x\r\n\r\n", b"GET / HTTP/1.1\r\n: x\r\n\r\n", b"GET / HTTP/1.1\r\nA: b\r\n folded\r\n\r\n", b"GET / HTTP/1.1\r\nA: b\nc\r\n\r\n"] {
+            assert!(matches!(parse_request(bad, 100), Err(ParseError::BadHeader)), "{:?}", String::from_utf8_lossy(bad));
+        }
+        assert!(matches!(parse_request(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n", 1), Err(ParseError::TooManyHeaders)));
+        assert!(parse_request(b"GET / HTTP/1.1\r\nA: 1\r\n\r\n", 1).is_ok());
+    }
+
+    #[test]
+    fn handle_request_sets_nodelay() {
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        assert!(!stream.nodelay().unwrap()); // Nagle's algorithm is on by default
+        client.write_all(b"GET /health HTTP/1.0\r\n\r\n").unwrap();
+        handle_request(&Arc::new(Common::default()), &stream, Peer(Some(peer))).unwrap();
+        assert!(stream.nodelay().unwrap());
+        drop(stream);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{response}");
+    }
+
+    #[test]
+    fn normalize_path_collapses_slashes_and_drops_a_trailing_one() {
+        assert!(matches!(normalize_path("/chat"), Cow::Borrowed("/chat")));
+        assert_eq!(normalize_path("//chat"), "/chat");
+        assert_eq!(normalize_path("/chat/"), "/chat");
+        assert_eq!(normalize_path("///chat//messages///"), "/chat/messages");
+        assert!(matches!(normalize_path("/"), Cow::Borrowed("/")));
+        assert_eq!(normalize_path("//"), "/");
+        assert_eq!(normalize_path("/a/../b/"), "/a/../b"); // left for `static_file_path` to refuse
+        assert_eq!(static_file_path(Path::new("static"), &normalize_path("//a/..//b")), None);
+    }
+
+    /// A slow reader's connection: each write fails with the next of `errors` (if any), before accepting everything.
+    struct Stalling { errors: VecDeque<io::ErrorKind>, written: Vec<u8> }
+
+    impl Write for Stalling {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(kind) = self.errors.pop_front() { return Err(kind.into()) }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn patient_writer_rides_out_stalls_but_not_broken_pipes() {
+        use io::ErrorKind::*;
+        let stalling = |errors: &[io::ErrorKind]| PatientWriter(Stalling { errors: errors.iter().copied().collect(), written: Vec::new() });
+
+        let mut w = stalling(&[WouldBlock, Interrupted, TimedOut, Interrupted]);
+        w.write_all(b"data: hello\n\n").unwrap();
+        assert_eq!(w.0.written, b"data: hello\n\n");
+
+        let mut w = stalling(&vec![TimedOut; WRITE_RETRIES as usize + 1]);
+        assert_eq!(w.write_all(b"data: hello\n\n").unwrap_err().kind(), TimedOut); // stalled too long - drop the subscriber
+        assert!(w.0.written.is_empty());
+
+        let mut w = stalling(&[BrokenPipe, TimedOut]);
+        assert_eq!(w.write_all(b"data: hello\n\n").unwrap_err().kind(), BrokenPipe); // gone, so no retries
+        assert_eq!(w.0.errors.len(), 1);
+    }
+
+    #[test]
+    fn absolute_form_targets_are_routed_on_their_path() {
+        assert_eq!(origin_form("/chat?room=a"), "/chat?room=a");
+        assert_eq!(origin_form("http://localhost/chat?room=a"), "/chat?room=a");
+        assert_eq!(origin_form("HTTPS://example.com:8443/"), "/");
+        assert_eq!(origin_form("http://example.com"), "/");
+        assert_eq!(origin_form("http://example.com?room=a"), "/?room=a");
+        assert_eq!(origin_form("ftp://example.com/chat"), "ftp://example.com/chat"); // not ours to route
+        assert_eq!(target_authority("http://user@Example.COM:8080/chat"), Some("user@Example.COM:8080"));
+        assert_eq!(target_authority("/chat"), None);
+        assert_eq!(normalize_host("user@Example.COM.:8080"), "example.com");
+        assert_eq!(normalize_host("[::1]:8080"), "[::1]");
+    }
+
+    #[test]
+    fn last_messages_spots_repeats_within_the_window() {
+        let mut last = LastMessages::default();
+        let (alice, bob) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        let window = Duration::from_millis(50);
+        assert!(!last.repeat(alice, "general", "hi", window));
+        assert!(last.repeat(alice, "general", "hi", window));
+        assert!(!last.repeat(bob, "general", "hi", window)); // someone else
+        assert!(!last.repeat(alice, "rust", "hi", window)); // somewhere else
+        assert!(!last.repeat(alice, "general", "hi again", window));
+        assert!(!last.repeat(alice, "general", "hi", window)); // not the last message any more
+        std::thread::sleep(window);
+        assert!(!last.repeat(alice, "general", "hi", window));
+        assert!(!last.repeat(alice, "general", "hi", Duration::ZERO)); // disabled
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT"); // RFC 7231 § 7.1.1.1's example
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date(&http_date(UNIX_EPOCH)), Some(0));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None); // obsolete formats are ignored, as if absent
+        assert_eq!(parse_http_date("Sun, 6 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+    }
+
+    #[test]
+    fn mask_banned_words_matches_whole_words_case_insensitively() {
+        let banned = [String::from("darn"), String::from("heck")];
+        assert_eq!(mask_banned_words("Darn it, what the HECK!", &banned).as_deref(), Some("**** it, what the ****!"));
+        assert_eq!(mask_banned_words("darned heckler", &banned), None); // not whole words
+        assert_eq!(mask_banned_words("<darn>", &banned).as_deref(), Some("<****>"));
+        assert_eq!(mask_banned_words("darn", &[]), None);
+    }
+
+    #[test]
+    fn config_admits_by_cidr_rules_with_denials_winning() {
+        let rules = |allow: &[&str], deny: &[&str]| Config {
+            allow_ips:  allow.iter().map(|net| net.parse().unwrap()).collect(),
+            deny_ips:   deny.iter().map(|net| net.parse().unwrap()).collect(),
+            ..Config::default()
+        };
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        let config = rules(&[], &[]);
+        assert!(config.admits(ip("203.0.113.7")));
+        assert!(config.admits(ip("2001:db8::1")));
+
+        let config = rules(&["10.0.0.0/8", "fe80::/10"], &["10.1.0.0/16"]);
+        assert!(config.admits(ip("10.2.3.4")));
+        assert!(config.admits(ip("::ffff:10.2.3.4"))); // IPv4-mapped
+        assert!(config.admits(ip("fe80::1234")));
+        assert!(!config.admits(ip("10.1.2.3")));    // denied, though allowed
+        assert!(!config.admits(ip("11.0.0.1")));    // not allowed
+        assert!(!config.admits(ip("fec0::1")));
+
+        let config = rules(&[], &["127.0.0.1", "::/0"]);
+        assert!(!config.admits(ip("127.0.0.1")));
+        assert!(config.admits(ip("127.0.0.2")));
+        assert!(!config.admits(ip("::1")));
+
+        for bad in ["10.0.0.0/33", "::/129", "10.0.0.0/+8", "10.0.0/8", "host/8", "10.0.0.0/"] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn read_retrying_retries_interrupts_but_not_other_errors() {
+        struct Flaky(Vec<io::Result<&'static [u8]>>); // what each read does, in reverse
+        impl Read for Flaky {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let data = self.0.pop().unwrap_or(Ok(b""))?;
+                buf[..data.len()].copy_from_slice(data);
+                Ok(data.len())
+            }
+        }
+        let interrupted = || Err(io::Error::from(io::ErrorKind::Interrupted));
+        let mut flaky = Flaky(vec![Err(io::Error::from(io::ErrorKind::ConnectionReset)), Ok(b"data"), interrupted(), interrupted()]);
+        let mut buf = [0u8; 16];
+        assert_eq!(read_retrying(&mut flaky, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"data");
+        assert_eq!(read_retrying(&mut flaky, &mut buf).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(read_retrying(&mut flaky, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn unused_empty_rooms_are_forgotten_before_counting_towards_max_rooms() {
+        let common = Common::default();
+        let has = |name: &str| common.rooms.lock().unwrap().contains_key(name);
+        let held = common.room("held").unwrap();
+        common.post(&common.room("posted").unwrap(), "alice", "hi");
+        for i in 0 .. MAX_ROOMS { drop(common.room(&format!("visited-{i}"))) }
+        assert!(has("held") && has("posted"), "rooms in use or with scrollback are kept");
+        assert_eq!(common.rooms.lock().unwrap().len(), 3, "only the latest visited room is left to forget");
+
+        let busy = (0 .. MAX_ROOMS).map(|i| common.room(&format!("busy-{i}"))).collect::<Vec<_>>();
+        assert_eq!(busy.iter().filter(|room| room.is_none()).count(), 2, "held, posted, and the busy rooms fill every slot");
+        assert!(common.room("one-too-many").is_none());
+        drop((held, busy));
+        assert!(common.room("one-too-many").is_some());
+
+        assert!(common.existing_room("posted").is_some());
+        assert!(common.existing_room("never-used").is_none());
+        assert!(!has("never-used"), "looking doesn't create");
+    }
+}
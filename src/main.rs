@@ -1,195 +1,323 @@
-use std::io::{self, Read, Write, BufWriter};
-use std::net::{TcpStream, TcpListener};
+use rust_http_chat_server::{Config, Server, ctrl_c, log};
+use std::fmt::Display;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 use std::process::Command;
-use std::sync::{Mutex, Arc};
-use std::sync::mpsc::{Sender, channel, RecvTimeoutError};
+use std::str::FromStr;
 use std::time::Duration;
 
-const MAX_REQUEST   : usize = 64 * 1024; // 64 KiB - N.B. stack allocated
-const READ_TIMEOUT  : Duration = Duration::from_secs(10);
-const WRITE_TIMEOUT : Duration = Duration::from_secs(10);
-const SSE_TIMEOUT   : Duration = Duration::from_secs(10);
-
-#[derive(Default)]
-struct Common {
-    listeners: Mutex<Vec<Sender<Arc<String>>>>,
+/// Everything `main` needs from the command line and environment.
+struct Options {
+    open:               Option<String>, // path or URL
+    browser:            Option<String>, // command to `--open` with, instead of the platform default
+    binds:              Vec<(IpAddr, Option<u16>)>,
+    port:               u16,
+    workers:            usize,
+    max_connections:    Option<usize>, // or the library's default
+    tcp_keepalive:      Option<u64>, // seconds
+    log_level:          log::Level,
+    config:             Config,
 }
 
-fn main() -> io::Result<()> {
-    let mut args = std::env::args();
-    let _exe = args.next();
-    let mut open = false;
-    for arg in args {
-        match &*arg {
-            "--open"    => open = true,
-            _           => panic!("unexpected argument: {arg:?}"),
+/// Flags that can also be set via the environment - see [`Options::parse`].  Not `--open`, which only makes sense interactively, nor `--allow-raw` or `--no-server-header`, which take no value.
+const ENV_FLAGS : &[&str] = &[
+    "--bind", "--port", "--workers", "--max-connections", "--tcp-keepalive", "--read-timeout", "--write-timeout", "--sse-retry-ms",
+    "--ping-interval", "--history", "--backlog", "--max-message-bytes", "--max-request-size", "--max-headers", "--rate-limit",
+    "--rate-burst", "--dedup-window-ms", "--static-dir", "--index-file", "--auth", "--admin-token", "--history-file",
+    "--cors-origin", "--motd", "--motd-file", "--robots-file", "--banned-words-file", "--filter-mode", "--allow-ip", "--deny-ip",
+    "--server-name", "--vhost", "--log-level",
+];
+
+impl Options {
+    /// Settings from built-in defaults, overridden by `CHAT_*` environment variables, overridden in turn by `args` (excluding the executable).
+    ///
+    /// Each of [`ENV_FLAGS`] has an environment variable named after it - `--ping-interval 5` can be given as `CHAT_PING_INTERVAL=5`.
+    /// `CHAT_BIND` takes a comma separated list, and is replaced entirely by any `--bind` flags.
+    /// `CHAT_ALLOW_IP`, `CHAT_DENY_IP`, and `CHAT_VHOST` do too, but `--allow-ip`, `--deny-ip`, and `--vhost` add to them.
+    /// `--browser` defaults to the conventional `BROWSER` instead.
+    fn parse(args: impl IntoIterator<Item = String>, env: impl Fn(&str) -> Option<String>) -> Self {
+        let mut options = Self {
+            open:               None,
+            browser:            env("BROWSER"),
+            binds:              Vec::new(),
+            port:               80,
+            workers:            std::thread::available_parallelism().map_or(4, |n| n.get()),
+            max_connections:    None,
+            tcp_keepalive:      None,
+            log_level:          log::Level::Info,
+            config:             Config::default(),
+        };
+
+        let mut env_args = Vec::new();
+        for flag in ENV_FLAGS {
+            let var = format!("CHAT_{}", flag.trim_start_matches('-').replace('-', "_").to_ascii_uppercase());
+            let Some(value) = env(&var) else { continue };
+            let values = if matches!(*flag, "--bind" | "--allow-ip" | "--deny-ip" | "--vhost") { value.split(',').map(str::to_string).collect() } else { vec![value] };
+            for value in values { env_args.extend([flag.to_string(), value]) }
         }
+        options.parse_flags(env_args);
+        options.parse_flags(args);
+        options
     }
 
-    let common = Arc::new(Common::default());
-    let listener = TcpListener::bind("127.0.0.1:80")?;
-    if open {
-        std::thread::spawn(||{
-            let url = "http://localhost/";
-            let mut cmd : Command;
-            if cfg!(windows) {
-                cmd = Command::new("cmd");
-                cmd.args(&["/C", "start", "", url]);
-            } else if cfg!(target_os = "macos") {
-                cmd = Command::new("open");
-                cmd.args(&[url]);
-            } else if cfg!(target_os = "linux") {
-                cmd = Command::new("xdg-open");
-                cmd.args(&[url]);
-            } else {
-                // uhh... maybe?
-                eprintln!("\u{001B}[33;1mwarning\u{001B}[37m:\u{001B}[0m `--open` not specifically implemented for this platform, attempting to use `xdg-open`");
-                cmd = Command::new("xdg-open");
-                cmd.args(&[url]);
+    fn parse_flags(&mut self, args: impl IntoIterator<Item = String>) {
+        let mut args = args.into_iter().peekable();
+        let mut binds = Vec::new();
+        let config = &mut self.config;
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "--allow-raw" => config.allow_raw = true,
+                "--no-server-header" => config.server_name = None,
+                "--server-name" => config.server_name = Some(parse_arg(&arg, args.next())),
+                "--open"    => self.open = Some(args.next_if(|arg| !arg.starts_with("--")).unwrap_or_else(|| "/".into())),
+                "--browser" => self.browser = Some(parse_arg(&arg, args.next())),
+                "--bind"    => binds.push(parse_arg::<BindArg>(&arg, args.next()).0),
+                "--port"    => self.port = parse_arg(&arg, args.next()),
+                "--workers" => self.workers = parse_arg(&arg, args.next()),
+                "--max-connections" => self.max_connections = Some(parse_arg(&arg, args.next())),
+                "--tcp-keepalive" => self.tcp_keepalive = Some(parse_arg(&arg, args.next())),
+                "--read-timeout" => config.read_timeout = timeout(parse_arg(&arg, args.next())),
+                "--write-timeout" => config.write_timeout = timeout(parse_arg(&arg, args.next())),
+                "--sse-retry-ms" => config.sse_retry_ms = parse_arg(&arg, args.next()),
+                "--ping-interval" => config.ping_interval = Duration::from_secs(parse_arg(&arg, args.next())),
+                "--history" => config.history = parse_arg(&arg, args.next()),
+                "--backlog" => config.backlog = parse_arg(&arg, args.next()),
+                "--max-message-bytes" => config.max_message = parse_arg(&arg, args.next()),
+                "--max-request-size" => config.max_request = parse_arg(&arg, args.next()),
+                "--max-headers" => config.max_headers = parse_arg(&arg, args.next()),
+                "--rate-limit" => config.rate_limit = parse_arg(&arg, args.next()),
+                "--rate-burst" => config.rate_burst = parse_arg(&arg, args.next()),
+                "--dedup-window-ms" => config.dedup_window = Duration::from_millis(parse_arg(&arg, args.next())),
+                "--static-dir" => config.static_dir = Some(parse_arg(&arg, args.next())),
+                "--index-file" => config.index_file = Some(parse_arg(&arg, args.next())),
+                "--vhost" => config.vhosts.push(parse_arg::<VhostArg>(&arg, args.next()).0),
+                "--auth" => config.auth = Some(parse_arg(&arg, args.next())), // or CHAT_AUTH, to keep the password out of `ps`
+                "--admin-token" => config.admin_token = Some(parse_arg(&arg, args.next())), // or CHAT_ADMIN_TOKEN, likewise
+                "--history-file" => config.history_file = Some(parse_arg(&arg, args.next())),
+                "--cors-origin" => config.cors_origin = Some(parse_arg(&arg, args.next())),
+                "--motd" => config.motd = Some(parse_arg(&arg, args.next())),
+                "--motd-file" => config.motd = Some(read_file_arg(&arg, args.next()).trim_end().to_string()),
+                "--banned-words-file" => {
+                    let words = read_file_arg(&arg, args.next());
+                    config.banned_words = words.lines().map(str::trim).filter(|w| !w.is_empty() && !w.starts_with('#')).map(str::to_lowercase).collect();
+                },
+                "--filter-mode" => config.filter_mode = parse_arg(&arg, args.next()),
+                "--allow-ip" => config.allow_ips.push(parse_arg(&arg, args.next())),
+                "--deny-ip" => config.deny_ips.push(parse_arg(&arg, args.next())),
+                "--robots-file" => config.robots_txt = Some(read_file_arg(&arg, args.next())),
+                "--log-level" => self.log_level = parse_arg(&arg, args.next()),
+                _           => panic!("unexpected argument: {arg:?}"),
             }
-            cmd.status().unwrap();
-        });
+        }
+        if !binds.is_empty() { self.binds = binds }
     }
-    for stream in listener.incoming() {
-        let stream = stream?;
-        let common = Arc::clone(&common);
-        std::thread::spawn(move || {
-            if let Err(e) = handle_request(&common, &stream) {
-                match e.kind() {
-                    io::ErrorKind::TimedOut             => eprintln!("error handling connection: {:?}", e.kind()),
-                    io::ErrorKind::ConnectionAborted    => eprintln!("error handling connection: {:?}", e.kind()),
-                    _other                              => eprintln!("error handling connection: {e:?}"),
-                }
-            }
+}
+
+fn main() -> io::Result<()> {
+    let Options { open, browser, mut binds, port, workers, max_connections, tcp_keepalive, log_level, config } = Options::parse(std::env::args().skip(1), |var| std::env::var(var).ok());
+    log::set_level(log_level);
+    if binds.is_empty() { binds.push((IpAddr::V4(Ipv4Addr::LOCALHOST), None)) }
+    if workers == 0 { panic!("invalid value for argument \"--workers\": must be at least 1") }
+    if config.ping_interval.is_zero() { panic!("invalid value for argument \"--ping-interval\": must be at least 1") }
+    let addrs = binds.into_iter().map(|(ip, p)| SocketAddr::new(ip, p.unwrap_or(port))).collect::<Vec<_>>();
+
+    let mut server = Server::bind(&addrs)?;
+    server.workers(workers).config(config);
+    if let Some(max_connections) = max_connections { server.max_connections(max_connections); }
+    if let Some(idle) = tcp_keepalive { server.tcp_keepalive(Duration::from_secs(idle)); }
+    ctrl_c::install();
+    if let Some(open) = open {
+        let url = if open.contains("://") { open } else { local_url(addrs[0], &open) };
+        let mut cmd = open_command(std::env::consts::OS, &url, browser.as_deref());
+        std::thread::spawn(move ||{
+            let warning = match cmd.status() {
+                Ok(status) if status.success()  => return,
+                Ok(status)                      => format!("{cmd:?} failed ({status})"),
+                Err(err)                        => format!("unable to run {cmd:?} ({err})"),
+            };
+            if log::enabled(log::Level::Warn) { eprintln!("\u{001B}[33;1mwarning\u{001B}[37m:\u{001B}[0m `--open` {url}: {warning}") }
         });
     }
-    Ok(())
+
+    server.serve()
 }
 
-fn handle_request(common: &Common, mut stream: &TcpStream) -> io::Result<()> {
-    // https://developer.mozilla.org/en-US/docs/Web/HTTP/Resources_and_specifications
-    // https://datatracker.ietf.org/doc/html/rfc7230    Hypertext Transfer Protocol (HTTP/1.1): Message Syntax and Routing
-    // https://datatracker.ietf.org/doc/html/rfc7231    Hypertext Transfer Protocol (HTTP/1.1): Semantics and Content
-    // https://datatracker.ietf.org/doc/html/rfc6585    Additional HTTP Status Codes
-
-    stream.set_read_timeout(Some(READ_TIMEOUT))?;
-    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
-
-    let mut request = [0u8; MAX_REQUEST];
-    let mut read = 0;
-
-    loop {
-        if read == request.len() { return write!(stream, "HTTP/1.1 413 Payload Too Large\r\n\r\n") }
-        let prev_read = read;
-        let this_read = stream.read(&mut request[read..])?;
-        if this_read == 0 { return write!(stream, "HTTP/1.0 400 Bad Request\r\n\r\n") }
-        read += this_read;
-
-        let crlfcrlf_search_start = prev_read.saturating_sub(3);
-        if let Some(crlfcrlf_index) = request[crlfcrlf_search_start..].windows(4).position(|w| w == b"\r\n\r\n") {
-            let crlf_index = request.windows(2).position(|w| w == b"\r\n").unwrap();
-            let request_line = &request[..crlf_index];
-            let request_line = String::from_utf8_lossy(request_line);
-            let request_line = &*request_line;
-            eprintln!("request: {request_line:?}");
-
-            let crlfcrlf_index = crlfcrlf_index + crlfcrlf_search_start;
-            let header_lines = &request[crlf_index+2..(crlf_index+2).max(crlfcrlf_index + crlfcrlf_search_start)];
-            let header_lines = String::from_utf8_lossy(header_lines);
-            let header_lines = header_lines.split("\r\n");
-
-            // FIXME: should handle "Expect: 100-continue" header?
-            let mut content_length = None;
-            for header_line in header_lines {
-                if let Some((key, value)) = header_line.split_once(": ") {
-                    match key {
-                        "Content-Length" => {
-                            let length : usize = match value.parse() {
-                                Ok(n) => n,
-                                Err(_) => return write!(stream, "HTTP/1.0 400 Bad Request\r\n\r\n"),
-                            };
-                            content_length = Some(length);
-                        },
-                        _unknown => {},
-                    }
-                } else {
-                    //dbg!(header_line);
-                }
-            }
+/// The command `--open` should run to launch `url` on `os` (see [`std::env::consts::OS`]), without running it.
+///
+/// `browser` (from `--browser` or `BROWSER`) overrides the platform's default opener, and may include arguments to pass before the URL.
+fn open_command(os: &str, url: &str, browser: Option<&str>) -> Command {
+    let mut cmd : Command;
+    if let Some(browser) = browser.filter(|b| !b.trim().is_empty()) {
+        let mut args = browser.split_whitespace();
+        cmd = Command::new(args.next().unwrap_or_default());
+        cmd.args(args);
+        cmd.arg(url);
+    } else if os == "windows" {
+        cmd = Command::new("cmd");
+        cmd.args(["/C", "start", "", url]);
+    } else if os == "macos" {
+        cmd = Command::new("open");
+        cmd.args([url]);
+    } else if os == "linux" {
+        cmd = Command::new("xdg-open");
+        cmd.args([url]);
+    } else {
+        // uhh... maybe?
+        if log::enabled(log::Level::Warn) { eprintln!("\u{001B}[33;1mwarning\u{001B}[37m:\u{001B}[0m `--open` not specifically implemented for this platform, attempting to use `xdg-open`") }
+        cmd = Command::new("xdg-open");
+        cmd.args([url]);
+    }
+    cmd
+}
 
-            if let Some((method, (url, version))) = request_line.split_once(" ").map(|(m, u_v)| (m, u_v.split_once(" ").unwrap_or((u_v, "")))) {
-                let response_version = match version {
-                    "HTTP/0.9"                      => return write!(stream, "HTTP/1.0 426 Upgrade Required\r\nUpgrade: HTTP/1.1, HTTP/1.0\r\n\r\n"),
-                    "HTTP/1.0"                      => "HTTP/1.0",
-                    v if v.starts_with("HTTP/1.")   => "HTTP/1.1",
-                    v if v.starts_with("HTTP/")     => "HTTP/1.1",
-                    _                               => return write!(stream, "HTTP/1.0 505 HTTP Version Not Supported\r\n\r\n"),
-                };
-
-                let cargo_bin_name = env!("CARGO_BIN_NAME");
-                let mut w = BufWriter::new(stream);
-
-                return match url {
-                    "/" => {
-                        let index_html = include_str!("index.html");
-                        let index_html_len = index_html.len();
-
-                        // We "MUST" have a Date: header if reliable system time is available - but I've chosen to skip it.
-                        let headers = format!("Server: {cargo_bin_name}\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: {index_html_len}\r\n");
-                        match method {
-                            "GET"   => write!(w, "{response_version} 200 OK\r\n{headers}\r\n{index_html}"),
-                            "HEAD"  => write!(w, "{response_version} 200 OK\r\n{headers}\r\n"),
-                            _       => write!(w, "{response_version} 405 Method Not Allowed\r\nAllow: GET, HEAD\r\n\r\n"),
-                        }
-                    },
-                    "/chat" => {
-                        // We "MUST" have a Date: header if reliable system time is available - but I've chosen to skip it.
-                        let headers = format!("Server: {cargo_bin_name}\r\nCache-Control: no-store\r\nContent-Type: text/event-stream; charset=UTF-8\r\n");
-                        match method {
-                            "HEAD" => write!(w, "{response_version} 200 OK\r\n{headers}\r\n"),
-                            "GET" => {
-                                let (sender, receiver) = channel();
-                                common.listeners.lock().unwrap().push(sender);
-                                write!(w, "{response_version} 200 OK\r\n{headers}\r\n")?;
-                                loop {
-                                    match receiver.recv_timeout(SSE_TIMEOUT) {
-                                        Ok(msg) => {
-                                            write!(w, "{msg}")?;
-                                            while let Ok(msg) = receiver.try_recv() {
-                                                write!(w, "{msg}")?;
-                                            }
-                                            w.flush()?;
-                                        },
-                                        Err(RecvTimeoutError::Disconnected) => return Ok(()),
-                                        Err(RecvTimeoutError::Timeout) => write!(w, "event: ping\ndata: ping\n\n")?,
-                                    }
-                                }
-                            },
-                            "POST" => {
-                                let message_start = crlfcrlf_index + 4;
-                                loop {
-                                    let message_len = read - message_start;
-                                    if message_len >= content_length.unwrap_or(!0) { break }
-                                    let this_read = stream.read(&mut request[read..])?;
-                                    if this_read == 0 { break }
-                                    read += this_read;
-                                }
-                                // TODO: cap request length based on Content-Length ?
-                                let message = &request[message_start..read];
-                                let message = String::from_utf8_lossy(message).to_owned();
-                                let message = message.lines().map(|line| format!("data: {line}\n")).collect::<Vec<_>>().join("");
-                                let message = Arc::new(format!("{message}\n"));
-                                common.listeners.lock().unwrap().retain(|l| l.send(message.clone()).is_ok());
-                                write!(w, "{response_version} 204 No Content\r\nServer: {cargo_bin_name}\r\n\r\n")
-                            },
-                            _ => write!(w, "{response_version} 405 Method Not Allowed\r\nAllow: GET, HEAD, POST\r\n\r\n"),
-                        }
-                    },
-                    _ => write!(w, "HTTP/1.0 404 Not Found\r\n\r\n"),
-                }
-            } else {
-                return write!(stream, "HTTP/1.0 400 Bad Request\r\n\r\n");
-            }
+/// `--bind` value: either `ADDR:PORT`, or a bare `ADDR` that uses `--port`.
+struct BindArg((IpAddr, Option<u16>));
+
+impl FromStr for BindArg {
+    type Err = std::net::AddrParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<SocketAddr>() {
+            Ok(addr)    => Ok(Self((addr.ip(), Some(addr.port())))),
+            Err(_)      => Ok(Self((s.parse()?, None))),
         }
     }
 }
+
+/// `--vhost` value: `HOST=PATH`, where `PATH` is a page - or a directory with an `index.html` - to serve `/` from for `HOST`.
+struct VhostArg((String, PathBuf));
+
+impl FromStr for VhostArg {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((host, path)) if !host.is_empty() && !path.is_empty() => Ok(Self((host.to_ascii_lowercase(), path.into()))),
+            _ => Err("expected HOST=PATH"),
+        }
+    }
+}
+
+/// A timeout of `secs` seconds - or none at all, for `0`.
+fn timeout(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+fn parse_arg<T: FromStr>(flag: &str, value: Option<String>) -> T where T::Err: Display {
+    let value = value.unwrap_or_else(|| panic!("expected a value after argument: {flag:?}"));
+    value.parse().unwrap_or_else(|err| panic!("invalid value for argument {flag:?}: {value:?} ({err})"))
+}
+
+/// The contents of the file named by `flag`'s value.
+fn read_file_arg(flag: &str, value: Option<String>) -> String {
+    let path = parse_arg::<String>(flag, value);
+    std::fs::read_to_string(&path).unwrap_or_else(|err| panic!("invalid value for argument {flag:?}: unable to read {path:?} ({err})"))
+}
+
+/// The URL `--open` should launch to reach `path` on a server bound to `addr` from this machine.
+fn local_url(addr: SocketAddr, path: &str) -> String {
+    let host = match addr.ip() {
+        ip if ip.is_unspecified() || ip.is_loopback()   => "localhost".to_string(),
+        IpAddr::V4(ip)                                  => ip.to_string(),
+        IpAddr::V6(ip)                                  => format!("[{ip}]"),
+    };
+    let path = path.trim_start_matches('/');
+    match addr.port() {
+        80      => format!("http://{host}/{path}"),
+        port    => format!("http://{host}:{port}/{path}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() }
+
+    #[test]
+    fn flags_override_environment_variables_which_override_defaults() {
+        let env = |var: &str| match var {
+            "CHAT_PORT"             => Some(String::from("8080")),
+            "CHAT_WORKERS"          => Some(String::from("3")),
+            "CHAT_PING_INTERVAL"    => Some(String::from("7")),
+            "CHAT_BIND"             => Some(String::from("127.0.0.1,[::1]:9000")),
+            "CHAT_DENY_IP"          => Some(String::from("10.0.0.0/8")),
+            _                       => None,
+        };
+
+        let options = Options::parse(args(&[]), env);
+        assert_eq!((options.port, options.workers, options.config.ping_interval), (8080, 3, Duration::from_secs(7)));
+        assert_eq!(options.binds, [("127.0.0.1".parse().unwrap(), None), ("::1".parse().unwrap(), Some(9000))]);
+        assert_eq!(options.config.history, Config::default().history); // neither given
+        assert_eq!(options.config.deny_ips.len(), 1);
+
+        let options = Options::parse(args(&["--port", "9090", "--bind", "0.0.0.0", "--deny-ip", "192.168.0.0/16", "--history", "5"]), env);
+        assert_eq!((options.port, options.workers, options.config.ping_interval), (9090, 3, Duration::from_secs(7)));
+        assert_eq!(options.binds, [("0.0.0.0".parse().unwrap(), None)]); // a replacement...
+        assert_eq!(options.config.deny_ips.len(), 2); // ...but added to
+        assert_eq!(options.config.history, 5);
+
+        let options = Options::parse(args(&[]), |_| None);
+        assert_eq!((options.port, options.config.ping_interval), (80, Config::default().ping_interval));
+        assert!(options.binds.is_empty()); // `main` binds localhost
+    }
+
+    #[test]
+    fn open_is_only_a_flag() {
+        let env = |var: &str| (var == "CHAT_OPEN").then(|| String::from("/"));
+        assert_eq!(Options::parse(args(&[]), env).open, None);
+        assert_eq!(Options::parse(args(&["--open"]), env).open.as_deref(), Some("/"));
+        assert_eq!(Options::parse(args(&["--open", "/chat", "--port", "1"]), env).open.as_deref(), Some("/chat"));
+        assert_eq!(Options::parse(args(&["--open", "--port", "1"]), env).open.as_deref(), Some("/"));
+    }
+
+    /// The program and arguments of `cmd`, without running it.
+    fn command_line(cmd: &Command) -> Vec<String> {
+        std::iter::once(cmd.get_program()).chain(cmd.get_args()).map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    #[test]
+    fn open_command_picks_the_platform_opener_unless_overridden() {
+        let url = "http://localhost:8080/";
+        assert_eq!(command_line(&open_command("windows", url, None)), ["cmd", "/C", "start", "", url]);
+        assert_eq!(command_line(&open_command("macos", url, None)), ["open", url]);
+        assert_eq!(command_line(&open_command("linux", url, None)), ["xdg-open", url]);
+        assert_eq!(command_line(&open_command("freebsd", url, None)), ["xdg-open", url]);
+        assert_eq!(command_line(&open_command("windows", url, Some("firefox --new-tab"))), ["firefox", "--new-tab", url]);
+        assert_eq!(command_line(&open_command("linux", url, Some("  "))), ["xdg-open", url]); // blank, so ignored
+    }
+
+    #[test]
+    fn browser_comes_from_the_flag_or_the_environment() {
+        let env = |var: &str| (var == "BROWSER").then(|| String::from("lynx"));
+        assert_eq!(Options::parse(args(&[]), env).browser.as_deref(), Some("lynx"));
+        assert_eq!(Options::parse(args(&["--browser", "w3m"]), env).browser.as_deref(), Some("w3m"));
+        assert_eq!(Options::parse(args(&[]), |_| None).browser, None);
+    }
+
+    #[test]
+    fn timeouts_are_seconds_with_0_for_none() {
+        let config = Options::parse(args(&[]), |_| None).config;
+        assert_eq!((config.read_timeout, config.write_timeout), (Some(Duration::from_secs(10)), Some(Duration::from_secs(10))));
+        let config = Options::parse(args(&["--read-timeout", "30", "--write-timeout", "0"]), |_| None).config;
+        assert_eq!((config.read_timeout, config.write_timeout), (Some(Duration::from_secs(30)), None));
+        let config = Options::parse(args(&["--read-timeout", "30"]), |var| (var == "CHAT_READ_TIMEOUT" || var == "CHAT_WRITE_TIMEOUT").then(|| String::from("0"))).config;
+        assert_eq!((config.read_timeout, config.write_timeout), (Some(Duration::from_secs(30)), None));
+        assert_eq!(config.ping_interval, Config::default().ping_interval); // separate from either
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid value for argument \"--read-timeout\"")]
+    fn timeouts_must_be_whole_seconds() {
+        Options::parse(args(&["--read-timeout", "1.5"]), |_| None);
+    }
+
+    #[test]
+    fn banned_words_file_is_a_lowercased_list_with_comments() {
+        let path = std::env::temp_dir().join(format!("rust_http_chat_server-banned-words-{}.txt", std::process::id()));
+        std::fs::write(&path, "# family friendly\nDarn\n\n  HECK  \n").unwrap();
+        let options = Options::parse(args(&["--banned-words-file", path.to_str().unwrap(), "--filter-mode", "mask"]), |_| None);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(options.config.banned_words, ["darn", "heck"]);
+        assert!(matches!(options.config.filter_mode, rust_http_chat_server::FilterMode::Mask));
+    }
+}
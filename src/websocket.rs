@@ -0,0 +1,96 @@
+//! Just enough of [RFC 6455] (The WebSocket Protocol) for text chat: the handshake's accept hash and frame (de)serialization.
+//! The session itself - what to do with those frames - lives alongside `stream_chat` in `lib.rs`.
+//!
+//! [RFC 6455]: https://datatracker.ietf.org/doc/html/rfc6455
+
+use std::io::{self, Read, Write};
+
+pub const CONTINUATION  : u8 = 0x0;
+pub const TEXT          : u8 = 0x1;
+pub const BINARY        : u8 = 0x2;
+pub const CLOSE         : u8 = 0x8;
+pub const PING          : u8 = 0x9;
+pub const PONG          : u8 = 0xA;
+
+pub struct Frame {
+    pub fin:        bool,
+    pub opcode:     u8,
+    pub payload:    Vec<u8>,
+}
+
+/// The `Sec-WebSocket-Accept` response header value for a request's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    super::base64_encode(&sha1(format!("{key}258EAFA5-E914-47DA-95CA-C5AB0DC85B11").as_bytes()))
+}
+
+/// Read and unmask one client to server frame.  Malformed frames, including payloads over `max_payload`, are [`io::ErrorKind::InvalidData`].
+pub fn read_frame(mut r: impl Read, max_payload: usize) -> io::Result<Frame> {
+    let invalid = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+
+    let mut head = [0u8; 2];
+    r.read_exact(&mut head)?;
+    let fin     = head[0] & 0x80 != 0;
+    let opcode  = head[0] & 0x0F;
+    if head[0] & 0x70 != 0  { return Err(invalid("reserved bits set without a negotiated extension")) }
+    if head[1] & 0x80 == 0  { return Err(invalid("client frames must be masked")) }
+    let len = match head[1] & 0x7F {
+        126 => { let mut len = [0; 2]; r.read_exact(&mut len)?; u64::from(u16::from_be_bytes(len)) },
+        127 => { let mut len = [0; 8]; r.read_exact(&mut len)?; u64::from_be_bytes(len) },
+        len => u64::from(len),
+    };
+    if opcode >= CLOSE && (!fin || len > 125)   { return Err(invalid("control frames must be short and unfragmented")) }
+    if len > max_payload as u64                 { return Err(invalid("frame too large")) }
+
+    let mut mask = [0u8; 4];
+    r.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload)?;
+    for (b, mask) in payload.iter_mut().zip(mask.iter().cycle()) { *b ^= mask }
+    Ok(Frame { fin, opcode, payload })
+}
+
+/// Write one unfragmented, unmasked server to client frame - in a single write, so frames from different threads can't interleave.
+pub fn write_frame(mut w: impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0 ..= 125         => frame.push(len as u8),
+        len @ 126 ..= 0xFFFF    => { frame.push(126); frame.extend((len as u16).to_be_bytes()) },
+        len                     => { frame.push(127); frame.extend((len as u64).to_be_bytes()) },
+    }
+    frame.extend(payload);
+    w.write_all(&frame)
+}
+
+/// SHA-1 ([RFC 3174](https://datatracker.ietf.org/doc/html/rfc3174)) - long broken for security purposes, but it's what the handshake uses.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h : [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 { message.push(0) }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (w, word) in w.iter_mut().zip(block.chunks(4)) { *w = u32::from_be_bytes([word[0], word[1], word[2], word[3]]) }
+        for i in 16 .. 80 { w[i] = (w[i-3] ^ w[i-8] ^ w[i-14] ^ w[i-16]).rotate_left(1) }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, w) in w.into_iter().enumerate() {
+            let (f, k) = match i {
+                0  ..= 19   => ((b & c) | (!b & d),             0x5A82_7999),
+                20 ..= 39   => (b ^ c ^ d,                      0x6ED9_EBA1),
+                40 ..= 59   => ((b & c) | (b & d) | (c & d),    0x8F1B_BCDC),
+                _           => (b ^ c ^ d,                      0xCA62_C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, temp);
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) { *h = h.wrapping_add(v) }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, h) in digest.chunks_mut(4).zip(h) { bytes.copy_from_slice(&h.to_be_bytes()) }
+    digest
+}
@@ -0,0 +1,150 @@
+//! Who may use the server: `--allow-ip`, `--deny-ip`, `--auth`, `--admin-token`, and the admin routes it unlocks.
+
+mod common;
+use common::*;
+use rust_http_chat_server::{Config, Server};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+
+fn with_auth() -> TestServer {
+    start(Config { auth: Some(String::from("alice:secret")), ..Config::default() })
+}
+
+#[test]
+fn basic_auth_missing_gets_401() {
+    let response = with_auth().get("/");
+    assert_eq!(status(&response), "HTTP/1.0 401 Unauthorized");
+    assert_eq!(header(&response, "WWW-Authenticate"), Some("Basic realm=\"chat\", charset=\"UTF-8\""));
+}
+
+#[test]
+fn basic_auth_wrong_password_gets_401() {
+    let response = with_auth().request("GET / HTTP/1.0\r\nAuthorization: Basic YWxpY2U6d3Jvbmc=\r\n\r\n"); // alice:wrong
+    assert_eq!(status(&response), "HTTP/1.0 401 Unauthorized");
+}
+
+#[test]
+fn basic_auth_correct_password_is_served() {
+    let response = with_auth().request("GET / HTTP/1.0\r\nAuthorization: basic YWxpY2U6c2VjcmV0\r\n\r\n"); // alice:secret
+    assert_eq!(status(&response), "HTTP/1.0 200 OK");
+}
+
+#[test]
+fn basic_auth_exempts_health_checks() {
+    assert_eq!(status(&with_auth().get("/health")), "HTTP/1.0 200 OK");
+}
+
+#[test]
+fn raw_sessions_are_refused_alongside_auth() {
+    let mut server = Server::bind(&[SocketAddr::from((Ipv4Addr::LOCALHOST, 0))]).unwrap();
+    server.config(Config { auth: Some(String::from("alice:secret")), allow_raw: true, ..Config::default() });
+    let error = server.serve().unwrap_err(); // rather than letting `CHAT` greetings past `--auth`
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn raw_sessions_chat_without_auth() {
+    let server = start(Config { allow_raw: true, ..Config::default() });
+    let mut raw = server.connect();
+    raw.write_all(b"CHAT general carol\r\n").unwrap();
+    eventually("the raw session has joined", || body(&server.get("/users?room=general")) == "1");
+    assert_eq!(status(&server.post("/chat", "hello")), "HTTP/1.0 204 No Content");
+    read_until(&mut raw, "anonymous: hello\r\n");
+    raw.write_all(b"hi back\r\n").unwrap();
+    let messages = || body(&server.get("/chat/messages")).to_string();
+    eventually("the raw session's line is posted", || messages().contains("\"text\":\"carol: hi back\""));
+}
+
+#[test]
+fn admin_routes_are_refused_without_the_token() {
+    let post = |server: &TestServer, authorization: &str| server.request(format!("POST /admin/clear?room=general HTTP/1.0\r\n{authorization}Content-Length: 0\r\n\r\n"));
+    let disabled = start(Config::default());
+    assert_eq!(status(&post(&disabled, "")), "HTTP/1.0 404 Not Found"); // no `--admin-token`, so no admin routes
+
+    let server = start(Config { admin_token: Some(String::from("hunter2")), ..Config::default() });
+    for authorization in ["", "Authorization: Bearer wrong\r\n", "Authorization: Basic aHVudGVyMg==\r\n"] {
+        let response = post(&server, authorization);
+        assert_eq!(status(&response), "HTTP/1.0 401 Unauthorized", "{authorization:?}");
+        assert_eq!(header(&response, "WWW-Authenticate"), Some("Bearer realm=\"admin\""));
+        assert_eq!(header(&response, "Connection"), Some("close"));
+    }
+    assert_eq!(status(&post(&server, "Authorization: Bearer hunter2\r\n")), "HTTP/1.0 204 No Content");
+    let response = server.request("POST /admin/clear?room=in%20valid HTTP/1.0\r\nAuthorization: Bearer hunter2\r\nContent-Length: 0\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.0 404 Not Found");
+}
+
+#[test]
+fn admin_shutdown_stops_the_process() {
+    let port = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port(); // free a moment ago
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rust_http_chat_server"))
+        .args(["--port", &port.to_string(), "--admin-token", "hunter2", "--log-level", "error"])
+        .env_clear() // no `CHAT_*` settings from whoever runs the tests
+        .spawn().unwrap();
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let started = std::time::Instant::now();
+    let mut stream = loop {
+        match std::net::TcpStream::connect(addr) {
+            Ok(stream) => break stream,
+            Err(_) if started.elapsed() < TIMEOUT => std::thread::sleep(std::time::Duration::from_millis(10)),
+            Err(e) => { let _ = child.kill(); panic!("server never listened: {e}") },
+        }
+    };
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.write_all(b"POST /admin/shutdown HTTP/1.0\r\nAuthorization: Bearer hunter2\r\nContent-Length: 0\r\n\r\n").unwrap();
+    assert_eq!(status(&read_to_close(&mut stream)), "HTTP/1.0 202 Accepted");
+
+    let exited = loop {
+        if let Some(status) = child.try_wait().unwrap() { break status }
+        if started.elapsed() > 2 * TIMEOUT { let _ = child.kill(); panic!("still running after /admin/shutdown") }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+    assert!(exited.success(), "{exited}");
+}
+
+#[test]
+fn denied_peers_get_403_and_allowed_ones_are_served() {
+    let denied = start(Config { allow_ips: vec!["127.0.0.0/8".parse().unwrap()], deny_ips: vec!["127.0.0.1".parse().unwrap()], ..Config::default() });
+    let response = read_to_close(&mut denied.connect()); // refused before any request is read
+    assert_eq!(status(&response), "HTTP/1.0 403 Forbidden");
+    let allowed = start(Config { allow_ips: vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()], ..Config::default() });
+    assert_eq!(status(&allowed.get("/health")), "HTTP/1.0 200 OK");
+}
+
+#[test]
+fn admin_subscribers_lists_open_streams() {
+    let server = start(Config { admin_token: Some(String::from("hunter2")), ..Config::default() });
+    let list = || server.request("GET /admin/subscribers HTTP/1.0\r\nAuthorization: Bearer hunter2\r\n\r\n");
+    assert_eq!(status(&server.get("/admin/subscribers")), "HTTP/1.0 401 Unauthorized");
+    assert_eq!(body(&list()), "[]\n");
+
+    let mut subscriber = server.subscribe("/chat?room=listed");
+    let peer = subscriber.local_addr().unwrap();
+    assert_eq!(status(&server.post("/chat?room=listed", "hello")), "HTTP/1.0 204 No Content");
+    read_until(&mut subscriber, "anonymous: hello");
+    let listed = || body(&list()).to_string();
+    eventually("both messages it was sent are counted", || listed().contains("\"messages\":2,")); // its own join, and the post
+    let listing = listed();
+    assert!(listing.starts_with(&format!("[{{\"peer\":\"{peer}\",\"room\":\"listed\",\"age_ms\":")), "{listing}");
+    assert_eq!(header(&list(), "Content-Type"), Some("application/json; charset=UTF-8"));
+
+    drop(subscriber);
+    eventually("the closed stream is no longer listed", || listed() == "[]\n");
+}
+
+#[test]
+fn chunked_admin_posts_close_rather_than_misread_their_chunks() {
+    let server = start(Config { admin_token: Some(String::from("hunter2")), ..Config::default() });
+    let chunked = "Authorization: Bearer hunter2\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+    for path in ["/admin/clear", "/admin/kick"] {
+        let response = server.request(format!("POST {path} HTTP/1.1\r\nHost: localhost\r\n{chunked}GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n"));
+        assert_eq!(status(&response), "HTTP/1.1 204 No Content", "{path}");
+        assert_eq!(header(&response, "Connection"), Some("close"), "{path}");
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "{path}: {response}"); // neither the chunks nor the pipelined GET are served
+    }
+
+    // Whereas a chunked message is decoded, so what's pipelined after it is served.
+    let response = server.request("POST /chat HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\nGET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.1 204 No Content");
+    assert_eq!(header(&response, "Connection"), Some("keep-alive"));
+    assert!(response.contains("\r\n\r\nHTTP/1.1 200 OK\r\n"), "{response}");
+}
@@ -0,0 +1,125 @@
+//! The chat page and other files served as-is - with ranges, caching, and virtual hosts.
+
+mod common;
+use common::*;
+use rust_http_chat_server::Config;
+
+const INDEX_HTML : &str = include_str!("../src/index.html");
+
+#[test]
+fn single_byte_range_gets_206() {
+    let server = start(Config::default());
+    let full = server.get("/");
+    assert_eq!(status(&full), "HTTP/1.0 200 OK");
+    assert_eq!(header(&full, "Accept-Ranges"), Some("bytes"));
+    assert_eq!(body(&full), INDEX_HTML);
+
+    let partial = server.request("GET / HTTP/1.0\r\nRange: bytes=0-14\r\n\r\n");
+    assert_eq!(status(&partial), "HTTP/1.0 206 Partial Content");
+    assert_eq!(header(&partial, "Content-Range"), Some(&*format!("bytes 0-14/{}", INDEX_HTML.len())));
+    assert_eq!(header(&partial, "Content-Length"), Some("15"));
+    assert_eq!(body(&partial), &INDEX_HTML[..15]);
+
+    let suffix = server.request("GET / HTTP/1.0\r\nRange: bytes=-8\r\n\r\n");
+    assert_eq!(status(&suffix), "HTTP/1.0 206 Partial Content");
+    assert_eq!(body(&suffix), &INDEX_HTML[INDEX_HTML.len() - 8 ..]);
+}
+
+#[test]
+fn unsatisfiable_range_gets_416() {
+    let server = start(Config::default());
+    let response = server.request(format!("GET / HTTP/1.0\r\nRange: bytes={}-\r\n\r\n", INDEX_HTML.len()));
+    assert_eq!(status(&response), "HTTP/1.0 416 Range Not Satisfiable");
+    assert_eq!(header(&response, "Content-Range"), Some(&*format!("bytes */{}", INDEX_HTML.len())));
+}
+
+#[test]
+fn unsupported_ranges_get_the_whole_body() {
+    let server = start(Config::default());
+    let response = server.request("GET / HTTP/1.0\r\nRange: bytes=0-1,5-6\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.0 200 OK");
+    assert_eq!(body(&response), INDEX_HTML);
+}
+
+#[test]
+fn matching_if_none_match_gets_304() {
+    let server = start(Config::default());
+    let first = server.get("/");
+    let etag = header(&first, "ETag").expect("an ETag");
+    let second = server.request(format!("GET / HTTP/1.0\r\nIf-None-Match: {etag}\r\n\r\n"));
+    assert_eq!(status(&second), "HTTP/1.0 304 Not Modified");
+    assert_eq!(header(&second, "ETag"), Some(etag));
+    assert_eq!(body(&second), "");
+
+    let other = server.request("GET / HTTP/1.0\r\nIf-None-Match: \"something else\"\r\n\r\n");
+    assert_eq!(status(&other), "HTTP/1.0 200 OK");
+}
+
+#[test]
+fn static_files_get_etags_too() {
+    let dir = temp_dir("static_files_get_etags_too");
+    std::fs::write(dir.join("hello.txt"), "hello").unwrap();
+    let server = start(Config { static_dir: Some(dir), ..Config::default() });
+    let first = server.get("/hello.txt");
+    assert_eq!(status(&first), "HTTP/1.0 200 OK");
+    assert_eq!(header(&first, "Content-Type"), Some("text/plain; charset=UTF-8"));
+    assert_eq!(body(&first), "hello");
+    let etag = header(&first, "ETag").expect("an ETag");
+    let second = server.request(format!("GET /hello.txt HTTP/1.0\r\nIf-None-Match: \"x\", {}\r\n\r\n", etag.trim_start_matches("W/")));
+    assert_eq!(status(&second), "HTTP/1.0 304 Not Modified");
+}
+
+#[test]
+fn if_modified_since_gets_304_unless_modified_since() {
+    let server = start(Config::default());
+    let first = server.get("/");
+    let last_modified = header(&first, "Last-Modified").expect("a Last-Modified");
+    let conditional = |since: &str| server.request(format!("GET / HTTP/1.0\r\nIf-Modified-Since: {since}\r\n\r\n"));
+    for since in [last_modified, "Fri, 01 Jan 2100 00:00:00 GMT"] {
+        let response = conditional(since);
+        assert_eq!(status(&response), "HTTP/1.0 304 Not Modified", "{since}");
+        assert_eq!(header(&response, "Last-Modified"), Some(last_modified));
+        assert_eq!(body(&response), "");
+    }
+    for since in ["Thu, 01 Jan 1970 00:00:00 GMT", "not a date"] {
+        assert_eq!(status(&conditional(since)), "HTTP/1.0 200 OK", "{since}");
+    }
+    // `If-None-Match` takes precedence.
+    let response = server.request("GET / HTTP/1.0\r\nIf-None-Match: \"something else\"\r\nIf-Modified-Since: Fri, 01 Jan 2100 00:00:00 GMT\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.0 200 OK");
+}
+
+#[test]
+fn head_gets_the_same_headers_as_get_without_the_body() {
+    let dir = temp_dir("head_gets_the_same_headers");
+    std::fs::write(dir.join("style.css"), "body { color: red }").unwrap();
+    let server = start(Config { static_dir: Some(dir), ..Config::default() });
+    assert_eq!(status(&server.post("/chat", "hello")), "HTTP/1.0 204 No Content");
+    let head_of = |response: &str| response.split("\r\n\r\n").next().unwrap().split("\r\n").filter(|line| !line.starts_with("Date: ")).map(str::to_string).collect::<Vec<_>>();
+    for path in ["/", "/health", "/users", "/favicon.ico", "/robots.txt", "/chat/messages", "/style.css"] {
+        let get = server.get(path);
+        let head = server.request(format!("HEAD {path} HTTP/1.0\r\n\r\n"));
+        assert_eq!(head_of(&head), head_of(&get), "{path}");
+        assert!(header(&get, "Content-Length").is_some(), "{path}");
+        assert_eq!(body(&head), "", "{path}");
+    }
+}
+
+#[test]
+fn vhosts_serve_their_own_index_pages() {
+    let dir = temp_dir("vhosts_serve_their_own_index_pages");
+    std::fs::write(dir.join("chat.html"), "chat page").unwrap();
+    std::fs::create_dir(dir.join("status")).unwrap();
+    std::fs::write(dir.join("status").join("index.html"), "status page").unwrap();
+    let server = start(Config {
+        vhosts: vec![(String::from("chat.example.com"), dir.join("chat.html")), (String::from("status.example.com"), dir.join("status"))],
+        ..Config::default()
+    });
+    let index = |host: &str| body(&server.request(format!("GET / HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n"))).to_string();
+    assert_eq!(index("chat.example.com"), "chat page");
+    assert_eq!(index("Chat.Example.COM:8080"), "chat page");
+    assert_eq!(index("status.example.com."), "status page");
+    assert_eq!(index("other.example.com"), INDEX_HTML);
+    assert_eq!(body(&server.request("GET http://status.example.com:80/ HTTP/1.1\r\nHost: chat.example.com\r\nConnection: close\r\n\r\n")), "status page"); // the absolute-form authority wins
+    assert_eq!(body(&server.get("/")), INDEX_HTML); // no Host at all
+}
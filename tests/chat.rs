@@ -0,0 +1,298 @@
+//! Posting to rooms, and what their subscribers receive.
+
+mod common;
+use common::*;
+use rust_http_chat_server::{Config, FilterMode};
+
+#[test]
+fn chunked_post_reaches_subscribers_reassembled() {
+    let server = start(Config::default());
+    let mut subscriber = server.subscribe("/chat");
+    let response = server.request("POST /chat HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n6\r\nhello \r\n5;ext=1\r\nworld\r\n0\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.1 204 No Content");
+    read_until(&mut subscriber, "data: anonymous: hello world\n");
+}
+
+#[test]
+fn malformed_chunked_post_is_refused() {
+    let server = start(Config::default());
+    let response = server.request("POST /chat HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nhello\r\n0\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.1 400 Bad Request");
+}
+
+#[test]
+fn post_with_lowercase_tightly_packed_headers_is_read() {
+    let server = start(Config::default());
+    let mut subscriber = server.subscribe("/chat");
+    let response = server.request("POST /chat HTTP/1.1\r\nhost:localhost\r\nconnection:close\r\ncontent-length:5\r\n\r\nhello");
+    assert_eq!(status(&response), "HTTP/1.1 204 No Content");
+    read_until(&mut subscriber, "data: anonymous: hello\n");
+}
+
+#[test]
+fn post_without_a_length_gets_411() {
+    let server = start(Config::default());
+    let response = server.request("POST /chat HTTP/1.1\r\nHost: localhost\r\n\r\nhello");
+    assert_eq!(status(&response), "HTTP/1.1 411 Length Required");
+}
+
+#[test]
+fn oversized_post_gets_413_without_being_read() {
+    let server = start(Config { max_message: 16, ..Config::default() });
+    let response = server.request("POST /chat HTTP/1.1\r\nHost: localhost\r\nContent-Length: 17\r\n\r\n"); // and no body
+    assert_eq!(status(&response), "HTTP/1.1 413 Payload Too Large");
+    assert!(body(&response).contains("Message exceeds 16 bytes"), "{response}");
+}
+
+#[test]
+fn post_body_ends_at_its_content_length() {
+    let server = start(Config::default());
+    let mut subscriber = server.subscribe("/chat");
+    let response = server.request("POST /chat HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhelloGET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.1 204 No Content");
+    assert!(response.ends_with("\r\n\r\nok"), "{response}");
+    read_until(&mut subscriber, "data: anonymous: hello\n\n");
+}
+
+#[test]
+fn websocket_frames_are_json_tagged_with_their_event() {
+    let server = start(Config::default());
+    let mut ws = server.websocket("/ws?room=general&user=bob");
+
+    assert_eq!(status(&server.post("/chat", "hi <b>")), "HTTP/1.0 204 No Content");
+    let message = websocket_recv_text(&mut ws, "\"event\":\"message\"");
+    assert!(message.starts_with("{\"id\":"), "{message}");
+    assert!(message.contains(",\"time\":"), "{message}");
+    assert!(message.ends_with(",\"text\":\"anonymous: hi <b>\"}"), "{message}"); // not HTML-escaped, unlike the SSE event's data
+
+    let json = "{\"user\":\"carol\",\"text\":\"hey\"}";
+    let response = server.request(format!("POST /chat HTTP/1.0\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{json}", json.len()));
+    assert_eq!(status(&response), "HTTP/1.0 204 No Content");
+    let chat = websocket_recv_text(&mut ws, "\"event\":\"chat\"");
+    assert!(chat.contains(",\"data\":{\"user\":\"carol\",\"text\":\"hey\",\"time\":"), "{chat}");
+
+    websocket_send(&mut ws, 0x1, b"from the socket");
+    let echoed = websocket_recv_text(&mut ws, "from the socket");
+    assert!(echoed.contains("\"event\":\"message\"") && echoed.contains("\"text\":\"bob: from the socket\""), "{echoed}");
+}
+
+#[test]
+fn users_counts_open_subscriptions() {
+    let server = start(Config::default());
+    let count = |path| body(&server.get(path)).to_string();
+    assert_eq!(count("/users"), "0");
+
+    let mut first = server.subscribe("/chat?room=counted");
+    let second = server.subscribe("/chat?room=counted&user=bob");
+    eventually("both subscriptions are counted", || count("/users") == "2"); // by their streams' own threads, once the headers are sent
+    assert_eq!(count("/users?room=counted"), "2");
+    assert_eq!(count("/users?room=elsewhere"), "0");
+    assert_eq!(count("/users?format=json"), "{\"count\":2}");
+    read_until(&mut first, "event: presence\ndata: {\"action\":\"join\",\"user\":\"bob\",\"count\":2}\n\n");
+
+    drop(second);
+    eventually("the closed subscription is no longer counted", || count("/users") == "1");
+    assert_eq!(count("/users?room=counted"), "1");
+    read_until(&mut first, "event: presence\ndata: {\"action\":\"leave\",\"user\":\"bob\",\"count\":1}\n\n");
+}
+
+#[test]
+fn messages_keep_their_timestamps_when_replayed_from_the_history_file() {
+    let history_file = temp_dir("messages_keep_their_timestamps").join("history.jsonl");
+    let config = || Config { history_file: Some(history_file.clone()), ..Config::default() };
+    let before = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+
+    let first = start(config());
+    let mut subscriber = first.subscribe("/chat");
+    assert_eq!(status(&first.post("/chat", "hello")), "HTTP/1.0 204 No Content");
+    let event = read_until(&mut subscriber, "data: anonymous: hello\n\n");
+    let event = &event[event.rfind("event: message\n").unwrap() ..];
+    let time = event.lines().nth(1).and_then(|line| line.strip_prefix("data: ")).unwrap(); // a field EventSource keeps, unlike `time:`
+    assert!(time.parse::<u64>().unwrap() >= before, "{event:?}");
+
+    std::thread::sleep(std::time::Duration::from_millis(5)); // so restamping would show
+    let second = start(config());
+    let messages = body(&second.get("/chat/messages")).to_string();
+    assert!(messages.contains(&format!("\"event\":\"message\",\"time\":{time},\"text\":\"anonymous: hello\"")), "{messages}");
+}
+
+#[test]
+fn posted_lines_cannot_forge_sse_fields() {
+    let server = start(Config::default());
+    let mut subscriber = server.subscribe("/chat");
+    assert_eq!(status(&server.post("/chat", "hi\nevent: ping\ndata: x\rretry: 1\r\nid: 99\r\r:comment")), "HTTP/1.0 204 No Content");
+    let event = read_until(&mut subscriber, "data: :comment\n\n");
+    let event = &event[event.rfind("event: message\n").unwrap() ..];
+    let mut lines = event.lines().skip(1); // `event: message`
+    assert!(lines.next().unwrap().strip_prefix("data: ").unwrap().parse::<u64>().is_ok(), "{event:?}"); // the time
+    assert_eq!(lines.collect::<Vec<_>>(), [
+        "data: anonymous: hi", "data: event: ping", "data: data: x", "data: retry: 1", "data: id: 99", "data: ", "data: :comment", "",
+    ]);
+}
+
+#[test]
+fn messages_is_not_a_room_name() {
+    let server = start(Config::default());
+    let response = server.post("/chat/messages", "hello"); // the poll endpoint's, rather than a room nobody could subscribe to
+    assert_eq!(status(&response), "HTTP/1.0 405 Method Not Allowed");
+    assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS"));
+    assert_eq!(status(&server.post("/chat?room=messages", "hello")), "HTTP/1.0 404 Not Found");
+    assert_eq!(status(&server.get("/chat/stream.ndjson?room=messages")), "HTTP/1.0 404 Not Found");
+}
+
+#[test]
+fn polling_returns_the_messages_after_since() {
+    let server = start(Config { history: 3, backlog: 3, ..Config::default() });
+    let poll = |since: &str| server.get(&format!("/chat/messages?room=polled&since={since}"));
+    let post = |n: u32| assert_eq!(status(&server.post("/chat/polled", &format!("message {n}"))), "HTTP/1.0 204 No Content");
+    let ids = |response: &str| body(response).split("\"id\":").skip(1).map(|id| id.split(',').next().unwrap().to_string()).collect::<Vec<_>>();
+
+    assert_eq!(status(&poll("0")), "HTTP/1.0 204 No Content"); // nothing yet
+    for n in 1 ..= 3 { post(n) }
+    let response = poll("0");
+    assert_eq!(status(&response), "HTTP/1.0 200 OK");
+    assert_eq!(header(&response, "Content-Type"), Some("application/json; charset=UTF-8"));
+    assert_eq!(ids(&response), ["1", "2", "3"]);
+    assert!(body(&response).contains("\"text\":\"anonymous: message 1\""), "{response}");
+    assert_eq!(ids(&poll("2")), ["3"]);
+    assert_eq!(status(&poll("3")), "HTTP/1.0 204 No Content"); // the latest
+    assert_eq!(status(&poll("100")), "HTTP/1.0 204 No Content"); // from the future?
+
+    // Only `--backlog` messages are retained, so polling from further back says how many were missed.
+    for n in 4 ..= 6 { post(n) }
+    let response = poll("1");
+    assert!(body(&response).starts_with("[{\"event\":\"gap\",\"missed\":2},{\"id\":4,"), "{response}");
+    assert_eq!(ids(&response), ["4", "5", "6"]);
+    assert_eq!(status(&poll("x")), "HTTP/1.0 400 Bad Request");
+}
+
+#[test]
+fn websocket_without_a_handshake_gets_426() {
+    let server = start(Config::default());
+    let response = server.get("/ws");
+    assert_eq!(status(&response), "HTTP/1.0 426 Upgrade Required");
+    assert_eq!(header(&response, "Upgrade"), Some("websocket"));
+    assert_eq!(header(&response, "Sec-WebSocket-Version"), Some("13"));
+}
+
+#[test]
+fn motd_is_the_first_event_after_any_scrollback_for_new_subscribers_only() {
+    let server = start(Config { motd: Some(String::from("Welcome to <#rust> - be nice")), ..Config::default() });
+    let motd = "event: system\ndata: Welcome to &lt;#rust&gt; - be nice";
+    // The events after the `retry:` preamble, up to and including the subscriber's own join.
+    let events_until_joined = |path| {
+        let events = read_until(&mut server.subscribe(path), "event: presence\n");
+        let (retry, events) = events.split_once("\n\n").unwrap();
+        assert!(retry.starts_with("retry: "), "{retry}");
+        events.split("\n\n").map(str::to_string).collect::<Vec<_>>()
+    };
+
+    // Escaped like any other message, and the very first event - before even the subscriber's own join.
+    let events = events_until_joined("/chat?room=quiet");
+    assert_eq!(events[0], motd, "{events:?}");
+    assert!(events[1].starts_with("event: presence\n"), "{events:?}");
+
+    // Unless there's scrollback, which it follows.
+    assert_eq!(status(&server.post("/chat", "earlier")), "HTTP/1.0 204 No Content");
+    let events = events_until_joined("/chat");
+    assert!(events[0].ends_with("\ndata: anonymous: earlier"), "{events:?}");
+    assert_eq!(events[1], motd, "{events:?}");
+    assert!(events[2].starts_with("event: presence\n"), "{events:?}");
+
+    // Resuming isn't joining, so there's no motd for subscribers reconnecting with an id.
+    let mut resumed = server.connect();
+    std::io::Write::write_all(&mut resumed, b"GET /chat HTTP/1.0\r\nLast-Event-ID: 1\r\n\r\n").unwrap();
+    read_until(&mut resumed, "\r\n\r\n");
+    assert_eq!(status(&server.post("/chat", "later")), "HTTP/1.0 204 No Content");
+    let events = read_until(&mut resumed, "data: anonymous: later\n\n");
+    assert!(!events.contains("event: system"), "{events}");
+}
+
+#[test]
+fn repeated_posts_are_accepted_but_only_broadcast_once() {
+    let window = std::time::Duration::from_millis(500);
+    let server = start(Config { dedup_window: window, ..Config::default() });
+    let count = || body(&server.get("/chat/messages?room=dedup")).matches("\"text\":\"anonymous: twice\"").count();
+    for _ in 0 .. 2 { assert_eq!(status(&server.post("/chat/dedup", "twice")), "HTTP/1.0 204 No Content") } // silently, as far as the poster knows
+    assert_eq!(count(), 1);
+    std::thread::sleep(window);
+    assert_eq!(status(&server.post("/chat/dedup", "twice")), "HTTP/1.0 204 No Content");
+    assert_eq!(count(), 2);
+}
+
+#[test]
+fn banned_words_are_rejected_or_masked() {
+    let banned = vec![String::from("darn"), String::from("heck")];
+    let messages = |server: &TestServer| body(&server.get("/chat/messages?room=filtered")).to_string();
+
+    let reject = start(Config { banned_words: banned.clone(), filter_mode: FilterMode::Reject, ..Config::default() });
+    let response = reject.post("/chat/filtered", "oh Darn");
+    assert_eq!(status(&response), "HTTP/1.0 422 Unprocessable Entity");
+    assert_eq!(status(&reject.post("/chat/filtered", "darned fine")), "HTTP/1.0 204 No Content");
+    assert!(!messages(&reject).contains("oh"), "{}", messages(&reject));
+
+    let mask = start(Config { banned_words: banned, filter_mode: FilterMode::Mask, ..Config::default() });
+    assert_eq!(status(&mask.post("/chat/filtered", "what the HECK <b>")), "HTTP/1.0 204 No Content");
+    assert!(messages(&mask).contains("\"text\":\"anonymous: what the **** <b>\""), "{}", messages(&mask));
+}
+
+#[test]
+fn subscribers_resuming_from_beyond_the_backlog_get_a_gap_event() {
+    let server = start(Config { history: 3, backlog: 3, ..Config::default() });
+    for n in 1 ..= 6 { assert_eq!(status(&server.post("/chat/gappy", &format!("message {n}"))), "HTTP/1.0 204 No Content") }
+
+    // Having seen message 1, they've missed 2 and 3 - and 4 ..= 6 follow.
+    let mut sse = server.connect();
+    std::io::Write::write_all(&mut sse, b"GET /chat/gappy HTTP/1.0\r\nLast-Event-ID: 1\r\n\r\n").unwrap();
+    let events = read_until(&mut sse, "data: anonymous: message 6\n\n");
+    let gap = events.find("event: gap\ndata: 2\n\n").expect("a gap event");
+    assert!(gap < events.find("id: 4\n").unwrap(), "{events}");
+
+    let mut ndjson = server.subscribe("/chat/stream.ndjson?room=gappy&since=1");
+    let lines = read_until(&mut ndjson, "\"text\":\"anonymous: message 6\"}\n");
+    assert!(lines.starts_with("{\"event\":\"gap\",\"missed\":2}\n{\"id\":4,"), "{lines}");
+}
+
+#[test]
+fn streams_are_never_gzipped_but_polled_messages_are() {
+    let server = start(Config::default());
+    let mut streams = ["/chat", "/chat/stream.ndjson"].map(|path| {
+        let mut stream = server.connect();
+        std::io::Write::write_all(&mut stream, format!("GET {path} HTTP/1.0\r\nAccept-Encoding: gzip\r\n\r\n").as_bytes()).unwrap();
+        let head = read_until(&mut stream, "\r\n\r\n");
+        assert_eq!(status(&head), "HTTP/1.0 200 OK");
+        assert_eq!(header(&head, "Content-Encoding"), None, "{path}");
+        stream
+    });
+    eventually("both streams have subscribed", || body(&server.get("/users")) == "2");
+    assert_eq!(status(&server.post("/chat", "plain")), "HTTP/1.0 204 No Content");
+    for stream in &mut streams { read_until(stream, "anonymous: plain"); } // as it's posted, not once a gzip block fills
+
+    let polled = server.request("GET /chat/messages HTTP/1.0\r\nAccept-Encoding: gzip\r\n\r\n");
+    assert_eq!(header(&polled, "Content-Encoding"), Some("gzip"));
+    assert_eq!(header(&polled, "Vary"), Some("Accept-Encoding"));
+}
+
+#[test]
+fn polling_or_counting_rooms_does_not_use_them_up() {
+    let server = start(Config::default());
+    // More than the server keeps rooms for - pipelined on one connection, rather than waiting on the acceptor for each.
+    let mut requests = (0 .. 300).map(|i| format!("GET /chat/messages?room=polled{i}&since=1 HTTP/1.1\r\nHost: localhost\r\n\r\nGET /users?room=counted{i} HTTP/1.1\r\nHost: localhost\r\n\r\n")).collect::<String>();
+    requests += "GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let responses = server.request(requests);
+    assert_eq!(responses.matches("HTTP/1.1 204 No Content\r\n").count(), 300);
+    assert_eq!(responses.matches("\r\n\r\n0").count(), 300, "{responses}"); // nobody's in any of the counted rooms
+
+    // Rooms left with nobody in them, and nothing said - in two batches, neither of them too many rooms at once.
+    for batch in 0 .. 2 {
+        let visits = (0 .. 200).map(|i| {
+            let mut stream = server.connect();
+            std::io::Write::write_all(&mut stream, format!("GET /chat/visited{batch}-{i} HTTP/1.0\r\n\r\n").as_bytes()).unwrap();
+            stream
+        }).collect::<Vec<_>>();
+        for mut stream in visits { assert_eq!(status(&read_until(&mut stream, "\r\n\r\n")), "HTTP/1.0 200 OK") }
+        eventually("the visitors have left", || body(&server.get("/users")) == "0");
+    }
+    assert_eq!(status(&server.post("/chat/fresh", "still room for more")), "HTTP/1.0 204 No Content");
+    assert!(body(&server.get("/chat/messages?room=fresh")).contains("still room for more"));
+}
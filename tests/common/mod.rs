@@ -0,0 +1,181 @@
+//! What the integration tests share: a [`Server`] on an ephemeral loopback port, and raw HTTP over TCP to talk to it.
+#![allow(dead_code)] // each test crate uses only some of these
+
+use rust_http_chat_server::{Config, Server, ShutdownHandle, log};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Long enough for anything a test waits on, short enough that a hang fails rather than stalls the run.
+pub const TIMEOUT : Duration = Duration::from_secs(5);
+
+/// Shut down when dropped - without waiting for `serve` to return, unlike [`TestServer::stop`].
+pub struct TestServer {
+    pub addr:   SocketAddr,
+    shutdown:   ShutdownHandle,
+    serving:    Option<JoinHandle<std::io::Result<()>>>,
+}
+
+/// Serve `config` on a new port - see [`start_with`] to change other settings.
+pub fn start(config: Config) -> TestServer {
+    start_with(config, |_| {})
+}
+
+/// Serve `config` on a new port, after `setup` has changed anything else about the [`Server`].
+pub fn start_with(config: Config, setup: impl FnOnce(&mut Server)) -> TestServer {
+    log::set_level(log::Level::Error);
+    let mut server = Server::bind(&[SocketAddr::from((Ipv4Addr::LOCALHOST, 0))]).unwrap();
+    let addr = server.local_addrs().unwrap()[0];
+    server.workers(2).config(config);
+    setup(&mut server);
+    let shutdown = server.shutdown_handle();
+    let serving = std::thread::spawn(move || server.serve());
+    TestServer { addr, shutdown, serving: Some(serving) }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) { self.shutdown.shutdown() }
+}
+
+impl TestServer {
+    /// Shut down, and return what `serve` did once it has.
+    pub fn stop(mut self) -> std::io::Result<()> {
+        self.shutdown.shutdown();
+        self.serving.take().unwrap().join().unwrap()
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(self.addr).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        stream
+    }
+
+    /// Send `request` verbatim on a new connection, and return everything sent back until the server closes it.
+    pub fn request(&self, request: impl AsRef<[u8]>) -> String {
+        let mut stream = self.connect();
+        stream.write_all(request.as_ref()).unwrap();
+        read_to_close(&mut stream)
+    }
+
+    /// `GET path` over HTTP/1.0, which closes the connection after responding.
+    pub fn get(&self, path: &str) -> String {
+        self.request(format!("GET {path} HTTP/1.0\r\n\r\n"))
+    }
+
+    /// `POST path` over HTTP/1.0 with a `text/plain` `body`.
+    pub fn post(&self, path: &str, body: &str) -> String {
+        self.request(format!("POST {path} HTTP/1.0\r\nContent-Length: {}\r\n\r\n{body}", body.len()))
+    }
+
+    /// Subscribe to `path` (e.g. `/chat`), returning the connection once the response headers have arrived.
+    pub fn subscribe(&self, path: &str) -> TcpStream {
+        let mut stream = self.connect();
+        write!(stream, "GET {path} HTTP/1.0\r\n\r\n").unwrap();
+        let head = read_until(&mut stream, "\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.0 200 OK\r\n"), "{head}");
+        stream
+    }
+
+    /// Open a WebSocket to `path` (e.g. `/ws?room=general`), returning the connection once the handshake's done.
+    pub fn websocket(&self, path: &str) -> TcpStream {
+        let mut stream = self.connect();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        let head = read_until(&mut stream, "\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{head}");
+        assert_eq!(header(&head, "Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="), "{head}"); // RFC 6455 § 1.3's example
+        stream
+    }
+}
+
+/// Send a masked, unfragmented WebSocket frame - as clients must.
+pub fn websocket_send(stream: &mut TcpStream, opcode: u8, payload: &[u8]) {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0 ..= 125 => frame.push(0x80 | len as u8),
+        len             => { frame.push(0x80 | 126); frame.extend((len as u16).to_be_bytes()) },
+    }
+    frame.extend(mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, mask)| b ^ mask));
+    stream.write_all(&frame).unwrap();
+}
+
+/// The opcode and payload of the next (unmasked, unfragmented) WebSocket frame from the server.
+pub fn websocket_recv(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(head[0] & 0xF0, 0x80, "expected a final frame without reserved bits");
+    let len = match head[1] {
+        126 => { let mut len = [0; 2]; stream.read_exact(&mut len).unwrap(); usize::from(u16::from_be_bytes(len)) },
+        127 => { let mut len = [0; 8]; stream.read_exact(&mut len).unwrap(); u64::from_be_bytes(len) as usize },
+        len => usize::from(len),
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    (head[0] & 0x0F, payload)
+}
+
+/// The next WebSocket text frame from the server whose payload contains `needle`, skipping any others.
+pub fn websocket_recv_text(stream: &mut TcpStream, needle: &str) -> String {
+    loop {
+        let (opcode, payload) = websocket_recv(stream);
+        let text = String::from_utf8(payload).unwrap();
+        if opcode == 0x1 && text.contains(needle) { return text }
+    }
+}
+
+/// Everything `stream` sends until it's closed - or what arrived before [`TIMEOUT`], if it isn't.
+pub fn read_to_close(stream: &mut TcpStream) -> String {
+    let mut bytes = Vec::new();
+    let _ = stream.read_to_end(&mut bytes);
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// What `stream` sends up to and including `needle`, panicking if that takes longer than [`TIMEOUT`].
+pub fn read_until(stream: &mut TcpStream, needle: &str) -> String {
+    let start = Instant::now();
+    let mut bytes = Vec::new();
+    while !String::from_utf8_lossy(&bytes).contains(needle) {
+        let mut byte = [0];
+        match stream.read(&mut byte) {
+            Ok(1) if start.elapsed() < TIMEOUT  => bytes.push(byte[0]),
+            other                               => panic!("expected {needle:?}, got {:?} then {other:?}", String::from_utf8_lossy(&bytes)),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Retry `condition` until it's true, panicking if it's still false after [`TIMEOUT`] - for what the server does in the background.
+pub fn eventually(what: &str, mut condition: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < TIMEOUT, "timed out waiting until {what}");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// A new, empty directory for the test named `test` to put files in.
+pub fn temp_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust_http_chat_server-{test}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The status line of `response`, e.g. `HTTP/1.1 200 OK`.
+pub fn status(response: &str) -> &str {
+    response.split("\r\n").next().unwrap_or_default()
+}
+
+/// The value of `response`'s first `name` header, if any.
+pub fn header<'r>(response: &'r str, name: &str) -> Option<&'r str> {
+    let head = response.split("\r\n\r\n").next().unwrap_or_default();
+    head.split("\r\n").skip(1).filter_map(|line| line.split_once(": ")).find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+}
+
+/// Everything after `response`'s headers.
+pub fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+}
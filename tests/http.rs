@@ -0,0 +1,257 @@
+//! How requests are framed, parsed, and refused - over real connections.
+
+mod common;
+use common::*;
+use rust_http_chat_server::Config;
+use std::io::{Read, Write};
+
+#[test]
+fn content_length_that_would_overflow_is_refused() {
+    let server = start(Config::default());
+    let response = server.request("GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 18446744073709551615\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.1 413 Payload Too Large");
+    assert_eq!(header(&response, "Connection"), Some("close"));
+    assert_eq!(status(&server.get("/health")), "HTTP/1.0 200 OK"); // and nothing panicked
+}
+
+#[test]
+fn content_length_beyond_max_request_is_refused_for_any_method() {
+    let server = start(Config { max_request: 4096, ..Config::default() });
+    for method in ["GET", "POST", "DELETE"] {
+        let response = server.request(format!("{method} /chat HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4097\r\n\r\n"));
+        assert_eq!(status(&response), "HTTP/1.1 413 Payload Too Large", "{method}");
+    }
+}
+
+#[test]
+fn paths_are_percent_decoded_before_routing() {
+    let server = start(Config::default());
+    assert_eq!(status(&server.get("/%68ealth")), "HTTP/1.0 200 OK");
+    assert_eq!(status(&server.get("/chat%2Fgeneral")), "HTTP/1.0 404 Not Found"); // not `/chat/general`
+    assert_eq!(status(&server.get("/%zz")), "HTTP/1.0 400 Bad Request");
+}
+
+#[test]
+fn stalled_request_gets_408() {
+    let server = start(Config { read_timeout: Some(std::time::Duration::from_millis(200)), ..Config::default() });
+    let response = server.request("GET / HTTP/1.1\r\nHost: localhost\r\n");
+    assert_eq!(status(&response), "HTTP/1.0 408 Request Timeout");
+    assert_eq!(header(&response, "Connection"), Some("close"));
+}
+
+#[test]
+fn idle_keep_alive_connection_is_closed_without_a_response() {
+    let server = start(Config { read_timeout: Some(std::time::Duration::from_millis(200)), ..Config::default() });
+    let response = server.request("GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.1 200 OK");
+    assert!(!response.contains("408"), "{response}"); // only a request that was started is owed one
+}
+
+#[test]
+fn requests_pipelined_after_a_post_are_served() {
+    let server = start(Config::default());
+    let mut stream = server.connect();
+    let post = |body: &str| format!("POST /chat HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+    // All in one write - so the first read has both POSTs, and the GET after them.
+    let requests = format!("{}{}GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", post("first"), post("second"));
+    stream.write_all(requests.as_bytes()).unwrap();
+    let responses = read_to_close(&mut stream);
+    let statuses = responses.split("\r\n").filter(|line| line.starts_with("HTTP/")).collect::<Vec<_>>();
+    assert_eq!(statuses, ["HTTP/1.1 204 No Content", "HTTP/1.1 204 No Content", "HTTP/1.1 200 OK"], "{responses}");
+    assert!(responses.ends_with("\r\n\r\nok"), "{responses}");
+}
+
+#[test]
+fn requests_pipelined_after_a_post_body_that_arrives_later_are_served() {
+    let server = start(Config::default());
+    let mut stream = server.connect();
+    stream.write_all(b"POST /chat HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhe").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    stream.write_all(b"lloGET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let responses = read_to_close(&mut stream);
+    assert!(responses.starts_with("HTTP/1.1 204 No Content\r\n"), "{responses}");
+    assert!(responses.contains("\r\n\r\nHTTP/1.1 200 OK\r\n"), "{responses}");
+    assert!(responses.ends_with("\r\n\r\nok"), "{responses}");
+}
+
+#[test]
+fn serve_returns_once_shut_down() {
+    let server = start(Config::default());
+    let addr = server.addr;
+    let mut subscriber = server.subscribe("/chat");
+    let stopping = std::thread::spawn(move || server.stop());
+    read_until(&mut subscriber, "event: shutdown\ndata: bye\n\n");
+    drop(subscriber);
+    stopping.join().unwrap().unwrap();
+    assert!(std::net::TcpStream::connect(addr).is_err(), "still listening");
+}
+
+#[test]
+fn more_than_max_headers_get_431() {
+    let server = start(Config::default());
+    let with_headers = |count: usize| {
+        let headers = (1 ..= count).map(|i| format!("X-{i}: {i}\r\n")).collect::<String>();
+        server.request(format!("GET /health HTTP/1.0\r\n{headers}\r\n"))
+    };
+    let response = with_headers(200);
+    assert_eq!(status(&response), "HTTP/1.0 431 Request Header Fields Too Large");
+    assert!(body(&response).contains("More than 100 header fields"), "{response}");
+    assert_eq!(status(&with_headers(100)), "HTTP/1.0 200 OK");
+    assert_eq!(status(&with_headers(101)), "HTTP/1.0 431 Request Header Fields Too Large");
+
+    let strict = start(Config { max_headers: 2, ..Config::default() });
+    assert_eq!(status(&strict.request("GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")), "HTTP/1.1 200 OK");
+    assert_eq!(status(&strict.request("GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nX-Extra: 1\r\n\r\n")), "HTTP/1.0 431 Request Header Fields Too Large");
+}
+
+#[test]
+fn headers_filling_max_request_get_431() {
+    let server = start(Config { max_request: 4096, ..Config::default() });
+    // Exactly the limit and no CRLFCRLF, so it's all read before the response - unread data could make the close a reset.
+    let mut request = String::from("GET / HTTP/1.1\r\nHost: localhost\r\nX-Padding: ");
+    request.extend(std::iter::repeat_n('a', 4096 - request.len()));
+    let response = server.request(request);
+    assert_eq!(status(&response), "HTTP/1.0 431 Request Header Fields Too Large");
+    assert_eq!(header(&response, "Connection"), Some("close"));
+
+    // Whereas a declared body too large for the same limit is a 413.
+    let response = server.request("POST /chat HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5000\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.1 413 Payload Too Large");
+}
+
+#[test]
+fn connections_beyond_max_connections_get_503() {
+    let server = start_with(Config::default(), |server| { server.max_connections(2); });
+    let subscriber = server.subscribe("/chat"); // subscribers count against the limit too
+    let _idle = server.connect();
+    let response = server.get("/health"); // the acceptor takes connections in order, so the idle one's already counted
+    assert_eq!(status(&response), "HTTP/1.0 503 Service Unavailable");
+    assert_eq!(header(&response, "Connection"), Some("close"));
+
+    drop(subscriber);
+    eventually("a connection is freed up", || status(&server.get("/health")) == "HTTP/1.0 200 OK");
+}
+
+#[test]
+fn methods_are_validated_before_routing() {
+    let server = start(Config::default());
+    let response = server.request("FOO\x00BAR / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.0 400 Bad Request");
+
+    let response = server.request("PATCH /chat HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"); // known, just not routed
+    assert_eq!(status(&response), "HTTP/1.1 405 Method Not Allowed");
+    assert_eq!(header(&response, "Allow"), Some("GET, HEAD, POST, OPTIONS"));
+
+    for method in ["BREW", &"X".repeat(1000)] {
+        let response = server.request(format!("{method} /chat HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"));
+        assert_eq!(status(&response), "HTTP/1.1 501 Not Implemented", "{method}");
+    }
+}
+
+#[test]
+fn trace_is_never_echoed_and_connect_is_not_implemented() {
+    let server = start(Config::default());
+    let trace = |path| server.request(format!("TRACE {path} HTTP/1.1\r\nHost: localhost\r\nCookie: secret\r\nConnection: close\r\n\r\n"));
+    let response = trace("/chat");
+    assert_eq!(status(&response), "HTTP/1.1 405 Method Not Allowed");
+    assert_eq!(header(&response, "Allow"), Some("GET, HEAD, POST, OPTIONS"));
+    assert!(!response.contains("secret"), "{response}");
+    let response = trace("/nowhere");
+    assert_eq!(status(&response), "HTTP/1.1 404 Not Found"); // not a 405 with an empty `Allow`
+    assert!(!response.contains("secret"), "{response}");
+
+    let response = server.request("CONNECT localhost:443 HTTP/1.1\r\nHost: localhost:443\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), "HTTP/1.1 501 Not Implemented");
+}
+
+#[test]
+fn control_characters_in_the_head_get_400() {
+    let server = start(Config::default());
+    for request in [
+        "GET /\x00admin HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "GET /chat\rINFO forged log entry HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost: localhost\r\nX-Forged: a\rb\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost: localhost\r\nX-Null: a\x00b\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost: localhost\r\nX-\x01: a\r\n\r\n",
+    ] {
+        assert_eq!(status(&server.request(request)), "HTTP/1.0 400 Bad Request", "{request:?}");
+    }
+    // Tabs are whitespace in header values, so they're fine.
+    assert_eq!(status(&server.request("GET /health HTTP/1.1\r\nHost: localhost\r\nX-Tab: a\tb\r\nConnection: close\r\n\r\n")), "HTTP/1.1 200 OK");
+}
+
+#[test]
+fn connections_persist_by_version_and_connection_header() {
+    let server = start(Config::default());
+    for (version, connection, persists) in [
+        ("HTTP/1.0", "",                            false),
+        ("HTTP/1.0", "Connection: keep-alive\r\n",  true),
+        ("HTTP/1.1", "",                            true),
+        ("HTTP/1.1", "Connection: close\r\n",       false),
+    ] {
+        let case = format!("{version} {connection:?}");
+        let mut stream = server.connect();
+        write!(stream, "GET /health {version}\r\nHost: localhost\r\n{connection}\r\n").unwrap();
+        let response = read_until(&mut stream, "\r\n\r\nok");
+        assert_eq!(status(&response), format!("{version} 200 OK"), "{case}");
+        assert_eq!(header(&response, "Connection"), Some(if persists { "keep-alive" } else { "close" }), "{case}");
+        if persists {
+            write!(stream, "GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+            assert_eq!(status(&read_to_close(&mut stream)), "HTTP/1.1 200 OK", "{case}");
+        } else {
+            assert_eq!(stream.read(&mut [0]).unwrap(), 0, "{case}: expected the connection to be closed");
+        }
+    }
+}
+
+#[test]
+fn stray_slashes_are_routed_but_dot_segments_are_refused() {
+    let dir = temp_dir("stray_slashes");
+    std::fs::create_dir_all(dir.join("static")).unwrap();
+    std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+    let server = start(Config { static_dir: Some(dir.join("static")), ..Config::default() });
+    assert_eq!(status(&server.get("//health")), "HTTP/1.0 200 OK");
+    assert_eq!(status(&server.get("/health/")), "HTTP/1.0 200 OK");
+    assert_eq!(status(&server.get("/")), "HTTP/1.0 200 OK");
+    assert_eq!(status(&server.get("//")), "HTTP/1.0 200 OK");
+    for traversal in ["/../secret.txt", "//..//secret.txt", "/%2E%2E/secret.txt", "/x/%2e%2e/%2e%2e/secret.txt"] {
+        assert_eq!(status(&server.get(traversal)), "HTTP/1.0 400 Bad Request", "{traversal}");
+    }
+}
+
+#[test]
+fn absolute_form_targets_are_served() {
+    let server = start(Config::default());
+    let response = server.get("http://localhost/");
+    assert_eq!(status(&response), "HTTP/1.0 200 OK");
+    assert_eq!(header(&response, "Content-Type"), Some("text/html; charset=UTF-8"));
+    let mut subscriber = server.subscribe("http://localhost:1234/chat?room=absolute"); // whatever the authority says
+    assert_eq!(status(&server.post("http://localhost/chat/absolute", "hello")), "HTTP/1.0 204 No Content");
+    read_until(&mut subscriber, "data: anonymous: hello\n");
+}
+
+#[test]
+fn http_1_1_requires_exactly_one_host() {
+    let server = start(Config::default());
+    let get = |headers: &str| status(&server.request(format!("GET /health HTTP/1.1\r\n{headers}Connection: close\r\n\r\n"))).to_string();
+    assert_eq!(get("Host: localhost\r\n"), "HTTP/1.1 200 OK");
+    assert_eq!(get(""), "HTTP/1.1 400 Bad Request");
+    assert_eq!(get("Host: localhost\r\nHost: example.com\r\n"), "HTTP/1.1 400 Bad Request");
+    assert_eq!(status(&server.get("/health")), "HTTP/1.0 200 OK"); // HTTP/1.0 predates the requirement
+}
+
+#[test]
+fn expectations_other_than_100_continue_get_417() {
+    let server = start(Config::default());
+    let response = server.request("POST /chat HTTP/1.1\r\nHost: localhost\r\nExpect: 200-ok\r\nContent-Length: 5\r\n\r\nhello");
+    assert_eq!(status(&response), "HTTP/1.1 417 Expectation Failed");
+    assert_eq!(header(&response, "Connection"), Some("close"));
+    assert!(!body(&server.get("/chat/messages")).contains("hello"), "posted anyways");
+
+    // Whereas 100-continue (in any case) gets the interim response before the body's sent.
+    let mut stream = server.connect();
+    stream.write_all(b"POST /chat HTTP/1.1\r\nHost: localhost\r\nExpect: 100-Continue\r\nContent-Length: 5\r\nConnection: close\r\n\r\n").unwrap();
+    assert_eq!(status(&read_until(&mut stream, "\r\n\r\n")), "HTTP/1.1 100 Continue");
+    stream.write_all(b"hello").unwrap();
+    assert_eq!(status(&read_to_close(&mut stream)), "HTTP/1.1 204 No Content");
+}
@@ -0,0 +1,48 @@
+//! The `--workers` pool: how many threads a flood of connections costs.
+#![cfg(target_os = "linux")] // threads are counted via /proc
+
+mod common;
+use common::*;
+use rust_http_chat_server::Config;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// This process's threads - this file's only test, so no other test's server is counted too.
+fn threads() -> usize {
+    std::fs::read_dir("/proc/self/task").unwrap().count()
+}
+
+#[test]
+fn a_flood_of_connections_uses_a_bounded_number_of_threads() {
+    const WORKERS : usize = 2;
+    const CLIENTS : usize = 4;
+    let before = threads();
+    let server = start_with(Config::default(), |server| { server.workers(WORKERS); });
+    let bound = before + 1 + 1 + WORKERS; // `serve`'s thread, its one acceptor, and the workers - nothing here streams
+    let mut most = threads();
+
+    // Short requests, from a few clients at once.
+    let clients = (0 .. CLIENTS).map(|_| {
+        let addr = server.addr;
+        std::thread::spawn(move || for _ in 0 .. 25 {
+            let mut stream = std::net::TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+            stream.write_all(b"GET /health HTTP/1.0\r\n\r\n").unwrap();
+            assert_eq!(status(&read_to_close(&mut stream)), "HTTP/1.0 200 OK");
+        })
+    }).collect::<Vec<_>>();
+    while !clients.iter().all(|client| client.is_finished()) { most = most.max(threads()); }
+    for client in clients { client.join().unwrap() }
+    assert!(most <= bound + CLIENTS, "{most} threads for {WORKERS} workers");
+
+    // Requests that never finish, tying up every worker and filling the queue behind them.
+    let mut stalled = Vec::new();
+    for _ in 0 .. 100 {
+        let mut stream = server.connect();
+        stream.write_all(b"GET /health HTTP/1.1\r\n").unwrap();
+        stalled.push(stream);
+    }
+    let (start, mut most) = (Instant::now(), threads());
+    while start.elapsed() < Duration::from_millis(500) { most = most.max(threads()); }
+    assert!(most <= bound, "{most} threads for {WORKERS} workers, with {} connections open", stalled.len());
+}